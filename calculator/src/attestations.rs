@@ -0,0 +1,90 @@
+use ceramic_http_client::ceramic_event::StreamId;
+use models::{PointAttestations, PointAttestationsV2};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttestationVersion {
+    V1,
+    V2,
+}
+
+impl FromStr for AttestationVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "1" | "v1" => Ok(Self::V1),
+            "2" | "v2" => Ok(Self::V2),
+            _ => anyhow::bail!("Invalid attestation version {}", s),
+        }
+    }
+}
+
+/// An attestation model and the schema version its documents follow
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttestationModel {
+    pub model_id: StreamId,
+    pub version: AttestationVersion,
+}
+
+impl AttestationModel {
+    /// Parse a comma separated list of `model_id:version` pairs, e.g. `kjz...:v1,kjz...:v2`
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, anyhow::Error> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let (model_id, version) = s
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid attestation model {}", s))?;
+                Ok(Self {
+                    model_id: StreamId::from_str(model_id)?,
+                    version: AttestationVersion::from_str(version)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Attestations normalized from any supported schema version
+pub struct NormalizedAttestations {
+    pub attestations: PointAttestations,
+    pub recipient: Option<String>,
+}
+
+pub fn normalize(
+    version: AttestationVersion,
+    content: &str,
+) -> Result<NormalizedAttestations, serde_json::Error> {
+    match version {
+        AttestationVersion::V1 => Ok(NormalizedAttestations {
+            attestations: serde_json::from_str::<PointAttestations>(content)?,
+            recipient: None,
+        }),
+        AttestationVersion::V2 => {
+            let v2 = serde_json::from_str::<PointAttestationsV2>(content)?;
+            Ok(NormalizedAttestations {
+                recipient: v2.recipient.clone(),
+                attestations: v2.into(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_each_version() {
+        let v1 = r#"{"issuer":"did:key:a","issuer_verification":"sig","data":[{"value":1,"context":"depin","timestamp":"2024-03-01T00:00:00Z"}]}"#;
+        let v2 = r#"{"issuer":"did:key:a","issuerVerification":"sig","recipient":"did:key:b","data":[{"value":1,"context":"depin","timestamp":"2024-03-01T00:00:00Z"}]}"#;
+        let from_v1 = normalize(AttestationVersion::V1, v1).unwrap();
+        let from_v2 = normalize(AttestationVersion::V2, v2).unwrap();
+        assert_eq!(from_v1.attestations, from_v2.attestations);
+        assert_eq!(from_v1.recipient, None);
+        assert_eq!(from_v2.recipient, Some("did:key:b".to_string()));
+        assert!(normalize(AttestationVersion::V1, v2).is_err());
+    }
+}
@@ -1,37 +1,101 @@
+use crate::attestations::{
+    normalize, AttestationModel, AttestationVersion, NormalizedAttestations,
+};
+use crate::completions::{CompletionRule, Completions, DEFAULT_COMPLETION_RULES};
+use crate::holders::HolderAttestations;
 use crate::materialization_cache::MaterializationCache;
+use crate::metrics::CalculatorMetrics;
+use crate::referrals::{
+    ReferralGraph, DEFAULT_REFERRAL_DEPTH, DEFAULT_REFERRAL_SHARE_PERCENT, MAX_REFERRAL_DEPTH,
+};
+use crate::streaks::{streak_events, StreakRule};
 use crate::Ceramic;
 use base64::prelude::*;
 use ceramic_http_client::ceramic_event::{ssi, DidDocument, Jwk, StreamId};
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use models::PointAttestations;
+use models::{PointAttestation, PointAttestations};
 use schema::Event;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
 pub struct CalculatorParameters {
     pub attestation_issuer: DidDocument,
-    pub attestation_model_id: StreamId,
+    pub attestation_models: Vec<AttestationModel>,
     pub materialization_model_id: StreamId,
+    pub referral_depth: usize,
+    pub referral_share_percent: i64,
+    pub streaks: Vec<StreakRule>,
+    pub completions: Vec<CompletionRule>,
+}
+
+/// Calculator settings as configured, before they are parsed into `CalculatorParameters`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalculatorSettings {
+    /// Models in the `ATTESTATION_MODELS` list format, used instead of `attestation_model_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attestation_models: Option<String>,
+    pub attestation_model_id: String,
+    pub materialization_model_id: String,
+    pub attestation_issuer: String,
+    pub referral_depth: usize,
+    pub referral_share_percent: i64,
+    pub streaks: String,
+    pub completion_rules: String,
+}
+
+impl Default for CalculatorSettings {
+    fn default() -> Self {
+        Self {
+            attestation_models: None,
+            attestation_model_id: "kjzl6hvfrbw6c947qf7ucq427v0eocaq4no93zdtccy70o5gclmcvyxjbqrx8mo"
+                .to_string(),
+            materialization_model_id:
+                "kjzl6hvfrbw6c88slfzg2mw6jvin2hgv2v24tbl9u0xc97f4pr4755xjr2l6sck".to_string(),
+            attestation_issuer: "did:key:z6MkhER5181mt9PBCrnVvL9AcdWyzSzj4PLgGVKSFjJ8obMN"
+                .to_string(),
+            referral_depth: DEFAULT_REFERRAL_DEPTH,
+            referral_share_percent: DEFAULT_REFERRAL_SHARE_PERCENT,
+            streaks: String::new(),
+            completion_rules: DEFAULT_COMPLETION_RULES.to_string(),
+        }
+    }
 }
 
 impl CalculatorParameters {
-    pub fn new() -> Result<Self, anyhow::Error> {
-        let attestation_model_id = std::env::var("ATTESTATION_MODEL_ID").unwrap_or_else(|_| {
-            "kjzl6hvfrbw6c947qf7ucq427v0eocaq4no93zdtccy70o5gclmcvyxjbqrx8mo".to_string()
-        });
-        let materialization_model_id =
-            std::env::var("MATERIALIZATION_MODEL_ID").unwrap_or_else(|_| {
-                "kjzl6hvfrbw6c88slfzg2mw6jvin2hgv2v24tbl9u0xc97f4pr4755xjr2l6sck".to_string()
-            });
-        let attestation_issuer =
-            DidDocument::new(&std::env::var("ATTESTATION_ISSUER").unwrap_or_else(|_| {
-                "did:key:z6MkhER5181mt9PBCrnVvL9AcdWyzSzj4PLgGVKSFjJ8obMN".to_string()
-            }));
+    pub fn from_settings(settings: &CalculatorSettings) -> Result<Self, anyhow::Error> {
+        let attestation_models = match settings.attestation_models.as_ref() {
+            Some(v) => AttestationModel::parse_list(v)?,
+            None => vec![AttestationModel {
+                model_id: StreamId::from_str(&settings.attestation_model_id)?,
+                version: AttestationVersion::V1,
+            }],
+        };
+        if !(0..=100).contains(&settings.referral_share_percent) {
+            anyhow::bail!(
+                "Referral share must be a percentage, got {}",
+                settings.referral_share_percent
+            );
+        }
+        if settings.referral_depth > MAX_REFERRAL_DEPTH {
+            anyhow::bail!(
+                "Referral depth must be at most {}, got {}",
+                MAX_REFERRAL_DEPTH,
+                settings.referral_depth
+            );
+        }
         Ok(Self {
-            attestation_issuer,
-            attestation_model_id: StreamId::from_str(&attestation_model_id)?,
-            materialization_model_id: StreamId::from_str(&materialization_model_id)?,
+            attestation_issuer: DidDocument::new(&settings.attestation_issuer),
+            attestation_models,
+            materialization_model_id: StreamId::from_str(&settings.materialization_model_id)?,
+            referral_depth: settings.referral_depth,
+            referral_share_percent: settings.referral_share_percent,
+            streaks: StreakRule::parse_list(&settings.streaks)?,
+            completions: CompletionRule::parse_list(&settings.completion_rules)?,
         })
     }
 }
@@ -39,78 +103,274 @@ impl CalculatorParameters {
 pub struct Calculator {
     params: CalculatorParameters,
     cache: MaterializationCache,
+    holders: HolderAttestations,
+    referrals: ReferralGraph,
+    completions: Completions,
+    metrics: Arc<CalculatorMetrics>,
+    /// Whether the attestations stored before this calculator started have been loaded
+    loaded: bool,
+}
+
+/// An attestation document along with the holder it is credited to
+struct Attested {
+    holder: String,
+    attestation: PointAttestations,
+    stream_id: StreamId,
 }
 
 impl Calculator {
     pub fn new(params: CalculatorParameters, cli: Box<dyn Ceramic + Send + Sync>) -> Calculator {
-        let cache = MaterializationCache::new(&params.materialization_model_id, cli);
-        Self { params, cache }
+        Self::new_with_metrics(params, cli, Arc::default())
+    }
+
+    /// Create a calculator that counts its work in `metrics`
+    pub fn new_with_metrics(
+        params: CalculatorParameters,
+        cli: Box<dyn Ceramic + Send + Sync>,
+        metrics: Arc<CalculatorMetrics>,
+    ) -> Calculator {
+        let cache =
+            MaterializationCache::new(&params.materialization_model_id, cli, metrics.clone());
+        let referrals = ReferralGraph::new(params.referral_depth, params.referral_share_percent);
+        let completions = Completions::new(params.completions.clone());
+        Self {
+            params,
+            cache,
+            holders: HolderAttestations::default(),
+            referrals,
+            completions,
+            metrics,
+            loaded: false,
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<CalculatorMetrics> {
+        self.metrics.clone()
     }
 
     pub async fn process_event(&mut self, event: Event) -> Result<(), anyhow::Error> {
+        let counter = match self.process(event).await {
+            Ok(true) => &self.metrics.events_processed,
+            Ok(false) => &self.metrics.events_skipped,
+            Err(e) => {
+                CalculatorMetrics::inc(&self.metrics.events_failed);
+                return Err(e);
+            }
+        };
+        CalculatorMetrics::inc(counter);
+        Ok(())
+    }
+
+    /// Rebuild state from the attestations and materializations already stored in ceramic, so
+    /// that values computed across holders are never written from only the events seen since
+    /// starting
+    async fn load(&mut self) -> Result<(), anyhow::Error> {
+        if self.loaded {
+            return Ok(());
+        }
+        let mut loaded = 0;
+        for model in self.params.attestation_models.clone() {
+            let events = self.cache.ceramic().documents(&model.model_id).await?;
+            for event in events {
+                match self.attested(event).await {
+                    Ok(Some(attested)) => {
+                        self.holders.record(
+                            &attested.holder,
+                            &attested.attestation,
+                            &attested.stream_id,
+                        );
+                        self.referrals.record(
+                            &attested.holder,
+                            &attested.attestation,
+                            &attested.stream_id,
+                        );
+                        loaded += 1;
+                    }
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!("Skipping stored attestation: {}", e),
+                }
+            }
+        }
+        tracing::info!("Loaded {} stored attestations", loaded);
+        for points in self.cache.load().await? {
+            self.completions.restore(&points);
+        }
+        self.loaded = true;
+        Ok(())
+    }
+
+    /// Materialize the points for an event, returning whether the event was an attestation to
+    /// count rather than one to skip
+    async fn process(&mut self, event: Event) -> Result<bool, anyhow::Error> {
+        self.load().await?;
+        let Attested {
+            holder,
+            attestation,
+            stream_id: attestation_stream_id,
+        } = match self.attested(event).await? {
+            Some(attested) => attested,
+            None => return Ok(false),
+        };
+        let merged = self
+            .holders
+            .record(&holder, &attestation, &attestation_stream_id);
+        unique_events(&mut self.cache, &holder, &merged, &attestation_stream_id).await?;
+        all_events(&mut self.cache, &holder, &merged, &attestation_stream_id).await?;
+        self.completions
+            .update(&mut self.cache, &holder, &merged, &attestation_stream_id)
+            .await?;
+        self.referrals
+            .update(
+                &mut self.cache,
+                &holder,
+                &attestation,
+                &attestation_stream_id,
+            )
+            .await?;
+        for rule in self.params.streaks.iter() {
+            streak_events(
+                &mut self.cache,
+                &holder,
+                &merged,
+                &attestation_stream_id,
+                rule,
+                &Utc::now(),
+            )
+            .await?;
+        }
+        Ok(true)
+    }
+
+    /// Recompute the streaks of every holder, so that a current streak ends once a period passes
+    /// without attestations rather than when the holder is next attested
+    pub async fn refresh_streaks(&mut self) -> Result<(), anyhow::Error> {
+        self.refresh_streaks_at(Utc::now()).await
+    }
+
+    async fn refresh_streaks_at(&mut self, now: DateTime<Utc>) -> Result<(), anyhow::Error> {
+        if self.params.streaks.is_empty() {
+            return Ok(());
+        }
+        self.load().await?;
+        for (holder, attestation, stream_id) in self.holders.merged()? {
+            for rule in self.params.streaks.iter() {
+                streak_events(
+                    &mut self.cache,
+                    &holder,
+                    &attestation,
+                    &stream_id,
+                    rule,
+                    &now,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The attestations of an event and their holder, or `None` if the event is not an
+    /// attestation to count
+    async fn attested(&self, event: Event) -> Result<Option<Attested>, anyhow::Error> {
         let meta: schema::CeramicMetadata = serde_json::from_value(event.metadata)?;
         let model = StreamId::from_str(&meta.model)?;
-        if model != self.params.attestation_model_id {
+        let version = if let Some(m) = self
+            .params
+            .attestation_models
+            .iter()
+            .find(|m| m.model_id == model)
+        {
+            m.version
+        } else {
             tracing::debug!("Skipping event for model {}", model);
-            return Ok(());
-        }
-        let holder = meta
-            .controllers
-            .into_iter()
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("No controllers for event"))?;
-        let attestation_stream_id = StreamId::from_str(&event.commit_id)?;
-        match serde_json::from_str::<PointAttestations>(&event.content) {
-            Ok(attestation) => {
+            return Ok(None);
+        };
+        let controller = meta.controllers.into_iter().next();
+        let stream_id = StreamId::from_str(&event.commit_id)?;
+        match normalize(version, &event.content) {
+            Ok(NormalizedAttestations {
+                attestations: attestation,
+                recipient,
+            }) => {
                 if attestation.issuer != self.params.attestation_issuer.id {
                     tracing::warn!(
                         "Attestation issuer {} does not match expected {}",
                         attestation.issuer,
                         self.params.attestation_issuer.id
                     );
-                    return Ok(());
+                    return Ok(None);
                 }
                 if let Err(e) = validate_attestation(&attestation).await {
-                    tracing::warn!("Error validating attestation: {}", e);
+                    tracing::warn!("Rejecting unverified attestation {}: {}", stream_id, e);
+                    return Ok(None);
                 }
-                unique_events(
-                    &mut self.cache,
-                    &holder,
-                    &attestation,
-                    &attestation_stream_id,
-                )
-                .await?;
-                all_events(
-                    &mut self.cache,
-                    &holder,
-                    &attestation,
-                    &attestation_stream_id,
-                )
-                .await?;
-                first_all_events(
-                    &mut self.cache,
-                    &holder,
-                    &attestation,
-                    &attestation_stream_id,
-                )
-                .await?;
+                let controller =
+                    controller.ok_or_else(|| anyhow::anyhow!("No controllers for event"))?;
+                // a recipient other than the controller is only credited when the issuer wrote
+                // the document, otherwise any controller could credit any DID
+                let holder = match recipient {
+                    Some(recipient)
+                        if recipient != controller && controller != attestation.issuer =>
+                    {
+                        tracing::warn!(
+                            "Rejecting attestation {} for {} written by {}",
+                            stream_id,
+                            recipient,
+                            controller
+                        );
+                        return Ok(None);
+                    }
+                    Some(recipient) => recipient,
+                    None => controller,
+                };
+                Ok(Some(Attested {
+                    holder,
+                    attestation,
+                    stream_id,
+                }))
             }
             Err(e) => {
-                tracing::warn!("Error parsing attestation: {}\n{}", e, event.content);
+                tracing::warn!(
+                    "Error parsing {:?} attestation: {}\n{}",
+                    version,
+                    e,
+                    event.content
+                );
+                Ok(None)
             }
         }
-        Ok(())
     }
 }
 
 async fn validate_attestation(attestation: &PointAttestations) -> Result<(), anyhow::Error> {
-    let did = DidDocument::new(&attestation.issuer);
-    let bytes = BASE64_STANDARD.decode(attestation.issuer_verification.as_bytes())?;
-    let sig = String::from_utf8_lossy(&bytes);
-    let (_, _, s) = ssi::jws::split_jws(&sig)?;
-    let jwk = Jwk::new(&did).await?;
-    ssi::jws::verify_bytes(ssi::jwk::Algorithm::EdDSA, &bytes, &jwk, s.as_bytes())?;
-
+    if attestation.issuer_verification.is_empty() {
+        anyhow::bail!("No issuer verification");
+    }
+    // a compact JWS, or the base64 encoding of one
+    let jws = if attestation.issuer_verification.split('.').count() == 3 {
+        attestation.issuer_verification.clone()
+    } else {
+        let bytes = BASE64_STANDARD.decode(attestation.issuer_verification.as_bytes())?;
+        String::from_utf8(bytes)?
+    };
+    let (header, payload, signature) = ssi::jws::split_jws(&jws)?;
+    // a detached JWS signs the data as serialized here
+    let jws = if payload.is_empty() {
+        let data = serde_json::to_vec(&attestation.data)?;
+        format!(
+            "{}.{}.{}",
+            header,
+            BASE64_URL_SAFE_NO_PAD.encode(data),
+            signature
+        )
+    } else {
+        jws.clone()
+    };
+    let jwk = Jwk::new(&DidDocument::new(&attestation.issuer)).await?;
+    let (_, payload) = ssi::jws::decode_verify(&jws, &jwk)?;
+    let signed: Vec<PointAttestation> = serde_json::from_slice(&payload)?;
+    if signed != attestation.data {
+        anyhow::bail!("Issuer signed other data");
+    }
     Ok(())
 }
 
@@ -164,19 +424,13 @@ async fn all_events(
     attestation: &PointAttestations,
     attestation_stream_id: &StreamId,
 ) -> Result<(), anyhow::Error> {
-    let keys: Vec<_> = attestation
-        .data
-        .iter()
-        .group_by(|d| &d.context)
-        .into_iter()
-        .map(|t| t.0)
-        .collect();
+    let count = attestation.data.len() as i64;
     match cache
         .get_points(holder, crate::calculator::ALL_EVENTS_CONTEXT)
         .await?
     {
         Some(mut existing) => {
-            existing.points.value = keys.len() as i64;
+            existing.points.value = count;
             tracing::info!(
                 "Updating points for {}: {:?}",
                 ALL_EVENTS_CONTEXT,
@@ -191,59 +445,335 @@ async fn all_events(
                 ALL_EVENTS_CONTEXT
             );
             cache
-                .create_points(
-                    holder,
-                    ALL_EVENTS_CONTEXT,
-                    attestation_stream_id,
-                    keys.len() as i64,
-                )
+                .create_points(holder, ALL_EVENTS_CONTEXT, attestation_stream_id, count)
                 .await?;
         }
     }
     Ok(())
 }
 
-const FIRST_ALL_EVENTS_CONTEXT: &str = "first-all-events";
-const TOTAL_EVENTS: usize = 9;
-async fn first_all_events(
-    cache: &mut MaterializationCache,
-    holder: &str,
-    attestation: &PointAttestations,
-    attestation_stream_id: &StreamId,
-) -> Result<(), anyhow::Error> {
-    let events_by_last_time: Vec<_> = attestation
-        .data
-        .iter()
-        .group_by(|d| &d.context)
-        .into_iter()
-        .flat_map(|(_, group)| {
-            group
-                .into_iter()
-                .sorted_by(|a, b| a.timestamp.cmp(&b.timestamp))
-                .next_back()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ceramic::mock::MockCeramic;
+    use crate::referrals::REFERRALS_CONTEXT;
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+
+    /// did:key of the ed25519 key in the first test vector of RFC 8032
+    const ISSUER: &str = "did:key:z6MktwupdmLXVVqTzCw4i46r4uGyosGXRnR3XjN4Zq7oMMsw";
+    const SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const ATTESTATION_MODEL: &str =
+        "kjzl6hvfrbw6c947qf7ucq427v0eocaq4no93zdtccy70o5gclmcvyxjbqrx8mo";
+    const ATTESTATION_MODEL_V2: &str =
+        "kjzl6hvfrbw6c947qf7ucq427v0eocaq4no93zdtccy70o5gclmcvyxjbqrx8kq";
+    const MATERIALIZATION_MODEL: &str =
+        "kjzl6hvfrbw6c88slfzg2mw6jvin2hgv2v24tbl9u0xc97f4pr4755xjr2l6sck";
+
+    fn model(id: &str) -> StreamId {
+        StreamId::from_str(id).unwrap()
+    }
+
+    fn params() -> CalculatorParameters {
+        CalculatorParameters {
+            attestation_issuer: DidDocument::new(ISSUER),
+            attestation_models: vec![AttestationModel {
+                model_id: model(ATTESTATION_MODEL),
+                version: AttestationVersion::V1,
+            }],
+            materialization_model_id: model(MATERIALIZATION_MODEL),
+            referral_depth: DEFAULT_REFERRAL_DEPTH,
+            referral_share_percent: DEFAULT_REFERRAL_SHARE_PERCENT,
+            streaks: vec![],
+            completions: vec![],
+        }
+    }
+
+    fn jwk() -> ssi::jwk::JWK {
+        let seed: Vec<u8> = (0..SEED.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&SEED[i..i + 2], 16).unwrap())
+            .collect();
+        ssi::jwk::ed25519_parse_private(&seed).unwrap()
+    }
+
+    /// Compact JWS by the issuer over the JSON of `data`, as the tester writes it
+    fn jws(data: &serde_json::Value) -> String {
+        ssi::jws::encode_sign(ssi::jwk::Algorithm::EdDSA, &data.to_string(), &jwk()).unwrap()
+    }
+
+    /// A v1 document of `data` signed by the issuer, with the JWS base64 encoded
+    fn signed(data: serde_json::Value) -> serde_json::Value {
+        json!({
+            "issuer": ISSUER,
+            "issuer_verification": BASE64_STANDARD.encode(jws(&data)),
+            "data": data,
         })
-        .sorted_by(|a, b| a.timestamp.cmp(&b.timestamp))
-        .rev()
-        .collect();
-    if events_by_last_time.len() >= TOTAL_EVENTS
-        && cache
-            .get_points(holder, FIRST_ALL_EVENTS_CONTEXT)
-            .await?
-            .is_none()
-    {
-        tracing::info!(
-            "Creating points for recipient {} for {}",
-            holder,
-            FIRST_ALL_EVENTS_CONTEXT
+    }
+
+    fn v1(value: i64, ref_id: Option<&str>) -> serde_json::Value {
+        signed(json!([{
+            "value": value,
+            "context": "proof-of-data",
+            "timestamp": "2024-03-01T00:00:00Z",
+            "refId": ref_id,
+        }]))
+    }
+
+    fn data(contexts: &[&str]) -> serde_json::Value {
+        contexts
+            .iter()
+            .map(|context| {
+                json!({
+                    "value": 1,
+                    "context": context,
+                    "timestamp": "2024-03-01T00:00:00Z",
+                })
+            })
+            .collect()
+    }
+
+    fn contexts(contexts: &[&str]) -> serde_json::Value {
+        signed(data(contexts))
+    }
+
+    fn v2(recipient: &str, contexts: &[&str]) -> serde_json::Value {
+        let data = data(contexts);
+        json!({
+            "issuer": ISSUER,
+            "issuerVerification": jws(&data),
+            "recipient": recipient,
+            "data": data,
+        })
+    }
+
+    fn params_v2() -> CalculatorParameters {
+        let mut params = params();
+        params.attestation_models.push(AttestationModel {
+            model_id: model(ATTESTATION_MODEL_V2),
+            version: AttestationVersion::V2,
+        });
+        params
+    }
+
+    #[tokio::test]
+    async fn merges_event_counts_across_schema_versions() {
+        let ceramic = MockCeramic::default();
+        let a = ceramic.add(
+            &model(ATTESTATION_MODEL),
+            "did:key:a",
+            contexts(&["a", "b"]),
         );
-        cache
-            .create_points(
-                holder,
-                FIRST_ALL_EVENTS_CONTEXT,
-                attestation_stream_id,
-                events_by_last_time.first().unwrap().timestamp.timestamp(),
-            )
-            .await?;
+        let b = ceramic.add(
+            &model(ATTESTATION_MODEL_V2),
+            "did:key:a",
+            v2("did:key:a", &["b", "c"]),
+        );
+
+        let mut calculator = Calculator::new(params_v2(), Box::new(ceramic.clone()));
+        calculator.process_event(a).await.unwrap();
+        calculator.process_event(b).await.unwrap();
+
+        let points = ceramic.points(&model(MATERIALIZATION_MODEL), "did:key:a");
+        assert_eq!(points.get("unique-events"), Some(&vec![3]));
+    }
+
+    #[tokio::test]
+    async fn credits_recipients_only_when_signed_by_the_writer_or_issuer() {
+        let ceramic = MockCeramic::default();
+        let mut unsigned = contexts(&["a"]);
+        unsigned["issuer_verification"] = "".into();
+        let events = vec![
+            ceramic.add(&model(ATTESTATION_MODEL), "did:key:a", unsigned),
+            ceramic.add(
+                &model(ATTESTATION_MODEL_V2),
+                "did:key:mallory",
+                v2("did:key:b", &["a"]),
+            ),
+            ceramic.add(
+                &model(ATTESTATION_MODEL_V2),
+                ISSUER,
+                v2("did:key:c", &["a"]),
+            ),
+        ];
+
+        let mut calculator = Calculator::new(params_v2(), Box::new(ceramic.clone()));
+        for event in events {
+            calculator.process_event(event).await.unwrap();
+        }
+
+        let materializations = model(MATERIALIZATION_MODEL);
+        assert!(ceramic.points(&materializations, "did:key:a").is_empty());
+        assert!(ceramic.points(&materializations, "did:key:b").is_empty());
+        assert!(ceramic
+            .points(&materializations, "did:key:mallory")
+            .is_empty());
+        assert_eq!(
+            ceramic.points(&materializations, "did:key:c")["unique-events"],
+            vec![1]
+        );
+        let metrics = calculator.metrics();
+        assert_eq!(metrics.events_skipped.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.events_processed.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_issuer_signatures() {
+        let ceramic = MockCeramic::default();
+        let jws = jws(&data(&["a"]));
+        let (header, payload, signature) = ssi::jws::split_jws(&jws).unwrap();
+        let tampered = if signature.starts_with('A') { "B" } else { "A" };
+        let tampered = format!("{}.{}.{}{}", header, payload, tampered, &signature[1..]);
+        let mut attestation = contexts(&["a"]);
+        attestation["issuer_verification"] = BASE64_STANDARD.encode(tampered).into();
+        let event = ceramic.add(&model(ATTESTATION_MODEL), "did:key:a", attestation);
+
+        let mut calculator = Calculator::new(params(), Box::new(ceramic.clone()));
+        calculator.process_event(event).await.unwrap();
+
+        assert!(ceramic
+            .points(&model(MATERIALIZATION_MODEL), "did:key:a")
+            .is_empty());
+        let metrics = calculator.metrics();
+        assert_eq!(metrics.events_skipped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn credits_only_the_data_the_issuer_signed() {
+        let ceramic = MockCeramic::default();
+        let mut copied = contexts(&["a"]);
+        copied["data"] = data(&["a", "b", "c"]);
+        // a detached JWS signs the data as the calculator serializes it
+        let signed: Vec<PointAttestation> = serde_json::from_value(data(&["a"])).unwrap();
+        let payload = serde_json::to_string(&signed).unwrap();
+        let jws = ssi::jws::encode_sign(ssi::jwk::Algorithm::EdDSA, &payload, &jwk()).unwrap();
+        let (header, _, signature) = ssi::jws::split_jws(&jws).unwrap();
+        let mut detached = contexts(&["a"]);
+        detached["issuer_verification"] = format!("{}..{}", header, signature).into();
+        let events = vec![
+            ceramic.add(&model(ATTESTATION_MODEL), "did:key:a", copied),
+            ceramic.add(&model(ATTESTATION_MODEL), "did:key:b", detached),
+        ];
+
+        let mut calculator = Calculator::new(params(), Box::new(ceramic.clone()));
+        for event in events {
+            calculator.process_event(event).await.unwrap();
+        }
+
+        let materializations = model(MATERIALIZATION_MODEL);
+        assert!(ceramic.points(&materializations, "did:key:a").is_empty());
+        assert_eq!(
+            ceramic.points(&materializations, "did:key:b")["unique-events"],
+            vec![1]
+        );
+    }
+
+    #[tokio::test]
+    async fn materializes_completions_once_per_holder() {
+        let ceramic = MockCeramic::default();
+        let attestations = model(ATTESTATION_MODEL);
+        let mut params = params();
+        params.completions = CompletionRule::parse_list("first-pair=a|b").unwrap();
+        let partial = ceramic.add(&attestations, "did:key:a", contexts(&["a"]));
+        let complete = ceramic.update(&partial, contexts(&["a", "b", "c"]));
+
+        let mut calculator = Calculator::new(params.clone(), Box::new(ceramic.clone()));
+        calculator.process_event(partial).await.unwrap();
+        calculator.process_event(complete.clone()).await.unwrap();
+        // a restarted calculator finds each of the holder's values by their context
+        let mut calculator = Calculator::new(params, Box::new(ceramic.clone()));
+        calculator.process_event(complete).await.unwrap();
+
+        let points = ceramic.points(&model(MATERIALIZATION_MODEL), "did:key:a");
+        let completed_at = chrono::DateTime::parse_from_rfc3339("2024-03-01T00:00:00Z")
+            .unwrap()
+            .timestamp();
+        assert_eq!(points.get("first-pair"), Some(&vec![completed_at]));
+        assert_eq!(points.get("first-pair-rank"), Some(&vec![1]));
+        assert_eq!(points.get("unique-events"), Some(&vec![3]));
+        assert_eq!(points.get("all-events"), Some(&vec![3]));
+    }
+
+    #[tokio::test]
+    async fn counts_every_event_and_each_context_once() {
+        let ceramic = MockCeramic::default();
+        let event = ceramic.add(
+            &model(ATTESTATION_MODEL),
+            "did:key:a",
+            contexts(&["a", "b", "a"]),
+        );
+
+        let mut calculator = Calculator::new(params(), Box::new(ceramic.clone()));
+        calculator.process_event(event).await.unwrap();
+
+        let points = ceramic.points(&model(MATERIALIZATION_MODEL), "did:key:a");
+        assert_eq!(points.get("all-events"), Some(&vec![3]));
+        assert_eq!(points.get("unique-events"), Some(&vec![2]));
+    }
+
+    #[tokio::test]
+    async fn ranks_completions_after_stored_ones() {
+        let ceramic = MockCeramic::default();
+        let attestations = model(ATTESTATION_MODEL);
+        let mut params = params();
+        params.completions = CompletionRule::parse_list("first-pair=a|b").unwrap();
+        let a = ceramic.add(&attestations, "did:key:a", contexts(&["a", "b"]));
+        let b = ceramic.add(&attestations, "did:key:b", contexts(&["a", "b"]));
+
+        let mut calculator = Calculator::new(params.clone(), Box::new(ceramic.clone()));
+        calculator.process_event(a).await.unwrap();
+        let mut calculator = Calculator::new(params, Box::new(ceramic.clone()));
+        calculator.process_event(b).await.unwrap();
+
+        let materializations = model(MATERIALIZATION_MODEL);
+        let rank = |holder| ceramic.points(&materializations, holder)["first-pair-rank"].clone();
+        assert_eq!(rank("did:key:a"), vec![1]);
+        assert_eq!(rank("did:key:b"), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn ends_current_streaks_without_new_attestations() {
+        let ceramic = MockCeramic::default();
+        let mut params = params();
+        params.streaks = StreakRule::parse_list("a:day").unwrap();
+        let now = Utc::now();
+        let mut data = data(&["a"]);
+        data[0]["timestamp"] = now.to_rfc3339().into();
+        let attestation = signed(data);
+        let event = ceramic.add(&model(ATTESTATION_MODEL), "did:key:a", attestation);
+
+        let mut calculator = Calculator::new(params, Box::new(ceramic.clone()));
+        calculator.process_event(event).await.unwrap();
+        let materializations = model(MATERIALIZATION_MODEL);
+        let streak = |context: &str| ceramic.points(&materializations, "did:key:a")[context][0];
+        assert_eq!(streak("a-current-streak"), 1);
+
+        let later = now + chrono::Duration::days(3);
+        calculator.refresh_streaks_at(later).await.unwrap();
+        assert_eq!(streak("a-current-streak"), 0);
+        assert_eq!(streak("a-longest-streak"), 1);
+        let metrics = calculator.metrics();
+        let updated = metrics.materializations_updated.load(Ordering::Relaxed);
+        calculator.refresh_streaks_at(later).await.unwrap();
+        assert_eq!(
+            metrics.materializations_updated.load(Ordering::Relaxed),
+            updated
+        );
+    }
+
+    #[tokio::test]
+    async fn awards_referrals_from_stored_attestations() {
+        let ceramic = MockCeramic::default();
+        let attestations = model(ATTESTATION_MODEL);
+        ceramic.add(&attestations, "did:key:a", v1(0, None));
+        let b = ceramic.add(&attestations, "did:key:b", v1(100, Some("did:key:a")));
+        ceramic.add(&attestations, "did:key:c", v1(1000, Some("did:key:b")));
+        let update = ceramic.update(&b, v1(200, Some("did:key:a")));
+
+        // a new calculator sees only the update, but awards from every stored attestation
+        let mut calculator = Calculator::new(params(), Box::new(ceramic.clone()));
+        calculator.process_event(update).await.unwrap();
+        let points = ceramic.points(&model(MATERIALIZATION_MODEL), "did:key:a");
+        assert_eq!(points.get(REFERRALS_CONTEXT), Some(&vec![20 + 10]));
     }
-    Ok(())
 }
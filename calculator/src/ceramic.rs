@@ -1,7 +1,14 @@
 use anyhow::Error;
-use ceramic_http_client::ceramic_event::StreamId;
+use ceramic_http_client::ceramic_event::{Cid, StreamId};
 use ceramic_http_client::{api, FilterQuery};
 use models::PointMaterialization;
+use schema::{Event, EventType};
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Path of ceramic's endpoint listing the documents of a model
+pub const COLLECTION_ENDPOINT: &str = "/api/v0/collection";
+const COLLECTION_PAGE_SIZE: u32 = 100;
 
 #[async_trait::async_trait]
 pub trait Ceramic {
@@ -21,4 +28,296 @@ pub trait Ceramic {
         stream_id: &StreamId,
         data: &PointMaterialization,
     ) -> Result<StreamId, Error>;
+    /// Post a request for a page of documents to [`COLLECTION_ENDPOINT`]
+    async fn collection(&self, request: serde_json::Value) -> Result<CollectionPage, Error>;
+
+    /// Every document of a model, as events carrying the document's latest content and its
+    /// controllers
+    async fn documents(&self, model_id: &StreamId) -> Result<Vec<Event>, Error> {
+        let mut events = vec![];
+        let mut after = None;
+        loop {
+            let page = self
+                .collection(CollectionPage::request(model_id, after.as_deref()))
+                .await?;
+            let (page_events, next) = page.into_events()?;
+            events.extend(page_events);
+            match next {
+                Some(cursor) => after = Some(cursor),
+                None => return Ok(events),
+            }
+        }
+    }
+}
+
+/// A page of documents from [`COLLECTION_ENDPOINT`]. Unlike `api::QueryResponse`, each document
+/// keeps its metadata, which names the controller an attestation is credited to.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionPage {
+    edges: Vec<CollectionEdge>,
+    page_info: PageInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct CollectionEdge {
+    node: CollectionNode,
+}
+
+#[derive(Debug, Deserialize)]
+struct CollectionNode {
+    content: serde_json::Value,
+    metadata: serde_json::Value,
+    log: Vec<LogEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LogEntry {
+    cid: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    #[serde(default)]
+    end_cursor: Option<String>,
+}
+
+impl CollectionPage {
+    /// Request for the page of a model's documents after `cursor`
+    pub fn request(model_id: &StreamId, after: Option<&str>) -> serde_json::Value {
+        let mut request = serde_json::json!({
+            "model": model_id.to_string(),
+            "first": COLLECTION_PAGE_SIZE,
+        });
+        if let Some(after) = after {
+            request["after"] = after.into();
+        }
+        request
+    }
+
+    /// The documents as events whose commit id is the document's stream id, along with the cursor
+    /// of the next page if there is one
+    pub fn into_events(self) -> Result<(Vec<Event>, Option<String>), Error> {
+        let events = self
+            .edges
+            .into_iter()
+            .map(|edge| {
+                let genesis = edge
+                    .node
+                    .log
+                    .first()
+                    .ok_or_else(|| anyhow::anyhow!("No log"))?;
+                let stream_id = StreamId::document(Cid::from_str(&genesis.cid)?);
+                Ok(Event {
+                    commit_id: stream_id.to_string(),
+                    event_type: EventType::Data,
+                    content: edge.node.content.to_string(),
+                    metadata: edge.node.metadata,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        let next = if self.page_info.has_next_page {
+            let cursor = self
+                .page_info
+                .end_cursor
+                .ok_or_else(|| anyhow::anyhow!("No cursor for next page"))?;
+            Some(cursor)
+        } else {
+            None
+        };
+        Ok((events, next))
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug)]
+    struct Document {
+        model_id: StreamId,
+        genesis: String,
+        stream_id: StreamId,
+        controller: String,
+        content: Value,
+    }
+
+    impl Document {
+        fn node(&self) -> Value {
+            json!({
+                "content": self.content,
+                "metadata": {
+                    "controllers": [self.controller],
+                    "model": self.model_id.to_string(),
+                },
+                "log": [{ "cid": self.genesis, "type": 0 }],
+            })
+        }
+
+        /// The document as the feed delivers it
+        fn event(&self) -> Event {
+            Event {
+                commit_id: self.stream_id.to_string(),
+                event_type: EventType::Data,
+                content: self.content.to_string(),
+                metadata: self.node()["metadata"].clone(),
+            }
+        }
+    }
+
+    /// Ceramic keeping documents in memory
+    #[derive(Clone, Default)]
+    pub struct MockCeramic {
+        documents: Arc<Mutex<Vec<Document>>>,
+    }
+
+    impl MockCeramic {
+        /// Store a new document, returning it as a feed event
+        pub fn add(&self, model_id: &StreamId, controller: &str, content: Value) -> Event {
+            let mut documents = self.documents.lock().unwrap();
+            let digest = [documents.len() as u8 + 1; 32];
+            let genesis = cid::Cid::new_v1(
+                0x71,
+                cid::multihash::Multihash::wrap(0x12, &digest).unwrap(),
+            )
+            .to_string();
+            let document = Document {
+                model_id: model_id.clone(),
+                stream_id: StreamId::document(Cid::from_str(&genesis).unwrap()),
+                genesis,
+                controller: controller.to_string(),
+                content,
+            };
+            documents.push(document.clone());
+            document.event()
+        }
+
+        /// Replace the content of a document, returning the update as a feed event
+        pub fn update(&self, event: &Event, content: Value) -> Event {
+            let mut documents = self.documents.lock().unwrap();
+            let document = documents
+                .iter_mut()
+                .find(|d| d.stream_id.to_string() == event.commit_id)
+                .unwrap();
+            document.content = content;
+            document.event()
+        }
+
+        /// Values of a model's documents for a recipient, by context
+        pub fn points(&self, model_id: &StreamId, recipient: &str) -> BTreeMap<String, Vec<i64>> {
+            let mut points: BTreeMap<_, Vec<_>> = BTreeMap::new();
+            for d in self.documents.lock().unwrap().iter() {
+                if &d.model_id == model_id && d.content["recipient"] == recipient {
+                    let context = d.content["context"].as_str().unwrap().to_string();
+                    points
+                        .entry(context)
+                        .or_default()
+                        .push(d.content["value"].as_i64().unwrap());
+                }
+            }
+            points
+        }
+    }
+
+    /// Whether content has the values compared by every field of a serialized filter
+    fn matches(filter: &Value, content: &Value) -> bool {
+        match filter {
+            Value::Object(fields) => fields.iter().all(|(key, value)| match content.get(key) {
+                Some(field) => compares(value, field),
+                None => matches(value, content),
+            }),
+            _ => false,
+        }
+    }
+
+    fn compares(filter: &Value, field: &Value) -> bool {
+        filter == field
+            || match filter {
+                Value::Object(values) => values.values().any(|v| compares(v, field)),
+                Value::Array(values) => values.iter().any(|v| compares(v, field)),
+                _ => false,
+            }
+    }
+
+    #[async_trait::async_trait]
+    impl Ceramic for MockCeramic {
+        async fn query(
+            &self,
+            model_id: &StreamId,
+            query: FilterQuery,
+        ) -> Result<api::QueryResponse, Error> {
+            let filter = serde_json::to_value(&query)?;
+            let edges: Vec<_> = self
+                .documents
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|d| &d.model_id == model_id && matches(&filter, &d.content))
+                .map(|d| json!({ "cursor": d.genesis, "node": d.node() }))
+                .collect();
+            Ok(serde_json::from_value(json!({
+                "edges": edges,
+                "pageInfo": {
+                    "hasNextPage": false,
+                    "hasPreviousPage": false,
+                    "startCursor": "",
+                    "endCursor": "",
+                },
+            }))?)
+        }
+
+        async fn create(
+            &self,
+            model_id: &StreamId,
+            data: &PointMaterialization,
+        ) -> Result<StreamId, Error> {
+            let event = self.add(model_id, "did:key:calculator", serde_json::to_value(data)?);
+            StreamId::from_str(&event.commit_id)
+        }
+
+        async fn replace(
+            &self,
+            _model_id: &StreamId,
+            stream_id: &StreamId,
+            data: &PointMaterialization,
+        ) -> Result<StreamId, Error> {
+            let mut documents = self.documents.lock().unwrap();
+            let document = documents
+                .iter_mut()
+                .find(|d| &d.stream_id == stream_id)
+                .ok_or_else(|| anyhow::anyhow!("No stream {}", stream_id))?;
+            document.content = serde_json::to_value(data)?;
+            Ok(stream_id.clone())
+        }
+
+        /// Pages of two documents, to exercise paging
+        async fn collection(&self, request: Value) -> Result<CollectionPage, Error> {
+            let start: usize = request["after"].as_str().unwrap_or("0").parse()?;
+            let documents: Vec<_> = self
+                .documents
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|d| d.model_id.to_string() == request["model"])
+                .cloned()
+                .collect();
+            let end = documents.len().min(start + 2);
+            let edges: Vec<_> = documents[start..end]
+                .iter()
+                .map(|d| json!({ "cursor": d.genesis, "node": d.node() }))
+                .collect();
+            Ok(serde_json::from_value(json!({
+                "edges": edges,
+                "pageInfo": {
+                    "hasNextPage": end < documents.len(),
+                    "endCursor": end.to_string(),
+                },
+            }))?)
+        }
+    }
 }
@@ -0,0 +1,221 @@
+use crate::materialization_cache::MaterializationCache;
+use ceramic_http_client::ceramic_event::StreamId;
+use chrono::{DateTime, Utc};
+use models::{PointAttestations, PointMaterialization};
+use std::collections::{BTreeSet, HashMap};
+
+/// Rule used when none are configured, awarding holders with attestations in any 9 contexts
+pub const DEFAULT_COMPLETION_RULES: &str = "first-all-events=any:9";
+
+/// Awarded once a holder has attestations for `minimum` of the contexts in `contexts`, or for
+/// `minimum` contexts of any name if `contexts` is empty
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompletionRule {
+    pub name: String,
+    pub contexts: BTreeSet<String>,
+    pub minimum: usize,
+}
+
+impl CompletionRule {
+    /// Parse a semicolon separated list of `name=context|context` rules requiring every named
+    /// context, or `name=any:count` rules requiring that many distinct contexts, e.g.
+    /// `first-pair=proof-of-data|depin;first-all-events=any:9`
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, anyhow::Error> {
+        value
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let (name, contexts) = s
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid completion rule {}", s))?;
+                let name = name.trim().to_string();
+                if let Some(minimum) = contexts.trim().strip_prefix("any:") {
+                    let minimum: usize = minimum.trim().parse()?;
+                    if minimum == 0 {
+                        anyhow::bail!("Completion rule {} requires no contexts", name);
+                    }
+                    return Ok(Self {
+                        name,
+                        contexts: BTreeSet::default(),
+                        minimum,
+                    });
+                }
+                let contexts: BTreeSet<_> = contexts
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|c| !c.is_empty())
+                    .map(|c| c.to_string())
+                    .collect();
+                if contexts.is_empty() {
+                    anyhow::bail!("Completion rule {} has no contexts", name);
+                }
+                Ok(Self {
+                    name,
+                    minimum: contexts.len(),
+                    contexts,
+                })
+            })
+            .collect()
+    }
+
+    fn rank_context(&self) -> String {
+        format!("{}-rank", self.name)
+    }
+
+    /// Time at which the last required context was first attested, if enough were attested
+    fn completed_at(&self, attestation: &PointAttestations) -> Option<DateTime<Utc>> {
+        let mut first_by_context: HashMap<&str, DateTime<Utc>> = HashMap::new();
+        for d in attestation
+            .data
+            .iter()
+            .filter(|d| self.contexts.is_empty() || self.contexts.contains(&d.context))
+        {
+            first_by_context
+                .entry(&d.context)
+                .and_modify(|t| *t = std::cmp::min(*t, d.timestamp))
+                .or_insert(d.timestamp);
+        }
+        let mut firsts: Vec<_> = first_by_context.into_values().collect();
+        firsts.sort();
+        firsts.get(self.minimum.checked_sub(1)?).copied()
+    }
+}
+
+/// Completion rules along with the number of holders that have completed each. Ranks are
+/// assigned in the order completions are observed, after those already materialized.
+pub struct Completions {
+    rules: Vec<CompletionRule>,
+    completed: HashMap<String, i64>,
+}
+
+impl Completions {
+    pub fn new(rules: Vec<CompletionRule>) -> Self {
+        Self {
+            rules,
+            completed: HashMap::default(),
+        }
+    }
+
+    /// Continue ranking after a rank that was already materialized
+    pub fn restore(&mut self, points: &PointMaterialization) {
+        for rule in self.rules.iter() {
+            if points.context == rule.rank_context() {
+                let rank = self.completed.entry(rule.name.clone()).or_default();
+                *rank = std::cmp::max(*rank, points.value);
+            }
+        }
+    }
+
+    pub async fn update(
+        &mut self,
+        cache: &mut MaterializationCache,
+        holder: &str,
+        attestation: &PointAttestations,
+        attestation_stream_id: &StreamId,
+    ) -> Result<(), anyhow::Error> {
+        for rule in self.rules.iter() {
+            let completed_at = if let Some(t) = rule.completed_at(attestation) {
+                t
+            } else {
+                continue;
+            };
+            if cache.get_points(holder, &rule.name).await?.is_some() {
+                continue;
+            }
+            let rank = self.completed.entry(rule.name.clone()).or_default();
+            *rank += 1;
+            tracing::info!(
+                "Creating points for recipient {} for {} with rank {}",
+                holder,
+                rule.name,
+                rank
+            );
+            cache
+                .create_points(
+                    holder,
+                    &rule.name,
+                    attestation_stream_id,
+                    completed_at.timestamp(),
+                )
+                .await?;
+            cache
+                .create_points(holder, &rule.rank_context(), attestation_stream_id, *rank)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use models::PointAttestation;
+
+    fn attestation(context: &str, hour: u32) -> PointAttestation {
+        PointAttestation {
+            value: 1,
+            context: context.to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 3, 1, hour, 0, 0).unwrap(),
+            ref_id: None,
+        }
+    }
+
+    #[test]
+    fn requires_every_named_context() {
+        let rule = &CompletionRule::parse_list("first-all=a|b|c").unwrap()[0];
+        let mut attestations = PointAttestations {
+            issuer: "did:key:issuer".to_string(),
+            issuer_verification: String::default(),
+            data: vec![
+                attestation("a", 5),
+                attestation("b", 3),
+                attestation("d", 1),
+                attestation("e", 2),
+                attestation("a", 1),
+            ],
+        };
+        assert_eq!(rule.completed_at(&attestations), None);
+        attestations.data.push(attestation("c", 4));
+        attestations.data.push(attestation("b", 9));
+        assert_eq!(
+            rule.completed_at(&attestations),
+            Some(Utc.with_ymd_and_hms(2024, 3, 1, 4, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn requires_count_of_any_contexts() {
+        let rule = &CompletionRule::parse_list(DEFAULT_COMPLETION_RULES).unwrap()[0];
+        let mut attestations = PointAttestations {
+            issuer: "did:key:issuer".to_string(),
+            issuer_verification: String::default(),
+            data: (0..8)
+                .map(|i| attestation(&format!("context-{}", i), i + 1))
+                .collect(),
+        };
+        attestations.data.push(attestation("context-0", 12));
+        assert_eq!(rule.completed_at(&attestations), None);
+        attestations.data.push(attestation("context-8", 10));
+        attestations.data.push(attestation("context-9", 11));
+        assert_eq!(
+            rule.completed_at(&attestations),
+            Some(Utc.with_ymd_and_hms(2024, 3, 1, 10, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = CompletionRule::parse_list("first=a|b; second = c; third=any:2").unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[1].name, "second");
+        assert_eq!(rules[1].contexts, BTreeSet::from(["c".to_string()]));
+        assert_eq!(rules[1].minimum, 1);
+        assert!(rules[2].contexts.is_empty());
+        assert_eq!(rules[2].minimum, 2);
+        assert!(CompletionRule::parse_list("first").is_err());
+        assert!(CompletionRule::parse_list("first=").is_err());
+        assert!(CompletionRule::parse_list("first=any:0").is_err());
+    }
+}
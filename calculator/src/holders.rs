@@ -0,0 +1,120 @@
+use ceramic_http_client::ceramic_event::StreamId;
+use models::PointAttestations;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+/// Attestations of each holder by the stream they are stored in, so that values are computed
+/// from every document of a holder rather than only the latest, whatever its schema version
+#[derive(Default)]
+pub struct HolderAttestations {
+    by_holder: HashMap<String, BTreeMap<String, PointAttestations>>,
+    stream_holders: HashMap<String, String>,
+}
+
+impl HolderAttestations {
+    /// Record the attestations of a stream, returning all of the holder's attestations merged
+    pub fn record(
+        &mut self,
+        holder: &str,
+        attestation: &PointAttestations,
+        attestation_stream_id: &StreamId,
+    ) -> PointAttestations {
+        let stream_id = attestation_stream_id.to_string();
+        if let Some(previous) = self
+            .stream_holders
+            .insert(stream_id.clone(), holder.to_string())
+        {
+            if previous != holder {
+                if let Some(streams) = self.by_holder.get_mut(&previous) {
+                    streams.remove(&stream_id);
+                }
+            }
+        }
+        let streams = self.by_holder.entry(holder.to_string()).or_default();
+        streams.insert(stream_id, attestation.clone());
+        merge(streams)
+    }
+
+    /// Every holder with their attestations merged, along with the stream of one of them
+    pub fn merged(&self) -> Result<Vec<(String, PointAttestations, StreamId)>, anyhow::Error> {
+        self.by_holder
+            .iter()
+            .filter_map(|(holder, streams)| {
+                let (stream_id, _) = streams.iter().next_back()?;
+                Some((holder, streams, stream_id))
+            })
+            .map(|(holder, streams, stream_id)| {
+                Ok((
+                    holder.clone(),
+                    merge(streams),
+                    StreamId::from_str(stream_id)?,
+                ))
+            })
+            .collect()
+    }
+}
+
+fn merge(streams: &BTreeMap<String, PointAttestations>) -> PointAttestations {
+    let first = streams.values().next();
+    PointAttestations {
+        issuer: first.map(|a| a.issuer.clone()).unwrap_or_default(),
+        issuer_verification: first
+            .map(|a| a.issuer_verification.clone())
+            .unwrap_or_default(),
+        data: streams
+            .values()
+            .flat_map(|a| a.data.iter().cloned())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::PointAttestation;
+
+    fn attestations(contexts: &[&str]) -> PointAttestations {
+        PointAttestations {
+            issuer: "did:key:issuer".to_string(),
+            issuer_verification: String::default(),
+            data: contexts
+                .iter()
+                .map(|context| PointAttestation {
+                    value: 1,
+                    context: context.to_string(),
+                    timestamp: chrono::Utc::now(),
+                    ref_id: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn contexts(attestations: &PointAttestations) -> Vec<&str> {
+        attestations
+            .data
+            .iter()
+            .map(|d| d.context.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn merges_streams_of_a_holder() {
+        let v1 =
+            StreamId::from_str("kjzl6kcym7w8y7nzgytqayf6aro12zt0mm01n6ydjomyvvklcspx9kr6gpbwd09")
+                .unwrap();
+        let v2 =
+            StreamId::from_str("kjzl6kcym7w8y5v2l4l6xlmvz0ojl8yq4ffa2c0xlm5nbi1dfpm2n0q1vwl7d4r")
+                .unwrap();
+        let mut holders = HolderAttestations::default();
+        holders.record("did:a", &attestations(&["a"]), &v1);
+        let merged = holders.record("did:a", &attestations(&["b"]), &v2);
+        assert_eq!(contexts(&merged), vec!["b", "a"]);
+        // a new version of a stream replaces that stream's attestations only
+        let merged = holders.record("did:a", &attestations(&["c"]), &v1);
+        assert_eq!(contexts(&merged), vec!["b", "c"]);
+        // a stream credited to another holder no longer counts for the previous one
+        holders.record("did:b", &attestations(&["c"]), &v1);
+        let merged = holders.record("did:a", &attestations(&["b"]), &v2);
+        assert_eq!(contexts(&merged), vec!["b"]);
+    }
+}
@@ -1,6 +1,17 @@
+mod attestations;
 mod calculator;
 mod ceramic;
+mod completions;
+mod holders;
 mod materialization_cache;
+mod metrics;
+mod referrals;
+mod streaks;
 
-pub use calculator::{Calculator, CalculatorParameters};
-pub use ceramic::Ceramic;
+pub use attestations::{AttestationModel, AttestationVersion};
+pub use calculator::{Calculator, CalculatorParameters, CalculatorSettings};
+pub use ceramic::{Ceramic, CollectionPage, COLLECTION_ENDPOINT};
+pub use completions::{CompletionRule, DEFAULT_COMPLETION_RULES};
+pub use metrics::CalculatorMetrics;
+pub use referrals::{DEFAULT_REFERRAL_DEPTH, DEFAULT_REFERRAL_SHARE_PERCENT, MAX_REFERRAL_DEPTH};
+pub use streaks::{StreakPeriod, StreakRule};
@@ -1,4 +1,5 @@
 use crate::ceramic::Ceramic;
+use crate::metrics::CalculatorMetrics;
 use anyhow::Error;
 use ceramic_http_client::api::QueryNode;
 use ceramic_http_client::ceramic_event::{Cid, StreamId};
@@ -6,6 +7,7 @@ use ceramic_http_client::{FilterQuery, OperationFilter};
 use models::PointMaterialization;
 use std::collections::{hash_map::Entry, HashMap};
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct ExistingPoints {
@@ -17,17 +19,50 @@ pub struct MaterializationCache {
     model_id: StreamId,
     cli: Box<dyn Ceramic + Send + Sync>,
     cache: HashMap<(String, String), ExistingPoints>,
+    metrics: Arc<CalculatorMetrics>,
 }
 
 impl MaterializationCache {
-    pub fn new(model_id: &StreamId, cli: Box<dyn Ceramic + Send + Sync>) -> Self {
+    pub fn new(
+        model_id: &StreamId,
+        cli: Box<dyn Ceramic + Send + Sync>,
+        metrics: Arc<CalculatorMetrics>,
+    ) -> Self {
         Self {
             model_id: model_id.clone(),
             cli,
             cache: HashMap::default(),
+            metrics,
         }
     }
 
+    pub fn ceramic(&self) -> &(dyn Ceramic + Send + Sync) {
+        self.cli.as_ref()
+    }
+
+    /// Fill the cache with the stored materializations, returning their points
+    pub async fn load(&mut self) -> Result<Vec<PointMaterialization>, Error> {
+        let mut loaded = vec![];
+        for event in self.cli.documents(&self.model_id).await? {
+            let points: PointMaterialization = match serde_json::from_str(&event.content) {
+                Ok(points) => points,
+                Err(e) => {
+                    tracing::warn!("Skipping stored materialization: {}", e);
+                    continue;
+                }
+            };
+            let stream_id = StreamId::from_str(&event.commit_id)?;
+            self.cache
+                .entry((points.recipient.clone(), points.context.clone()))
+                .or_insert_with(|| ExistingPoints {
+                    points: points.clone(),
+                    stream_id,
+                });
+            loaded.push(points);
+        }
+        Ok(loaded)
+    }
+
     pub async fn get_points(
         &mut self,
         subject: &str,
@@ -38,9 +73,13 @@ impl MaterializationCache {
             Entry::Vacant(entry) => {
                 let mut where_filter = HashMap::new();
                 where_filter.insert(
-                    "subject".to_string(),
+                    "recipient".to_string(),
                     OperationFilter::EqualTo(subject.into()),
                 );
+                where_filter.insert(
+                    "context".to_string(),
+                    OperationFilter::EqualTo(context.into()),
+                );
                 let filter = FilterQuery::Where(where_filter);
                 let resp = self.cli.query(&self.model_id, filter).await?;
                 let res: Result<Vec<_>, Error> =
@@ -70,6 +109,7 @@ impl MaterializationCache {
             point_claims_id: point_attestation_id.to_string(),
         };
         let stream_id = self.cli.create(&self.model_id, &points).await?;
+        CalculatorMetrics::inc(&self.metrics.materializations_created);
         let existing = ExistingPoints { points, stream_id };
         self.cache.insert(
             (
@@ -89,6 +129,7 @@ impl MaterializationCache {
             .cli
             .replace(&self.model_id, &existing.stream_id, &existing.points)
             .await?;
+        CalculatorMetrics::inc(&self.metrics.materializations_updated);
         let existing = ExistingPoints {
             points: existing.points,
             stream_id: updated_id,
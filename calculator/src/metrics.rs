@@ -0,0 +1,20 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts of the calculator's work, for export by the host
+#[derive(Debug, Default)]
+pub struct CalculatorMetrics {
+    /// Attestation events whose points were materialized
+    pub events_processed: AtomicU64,
+    /// Events ignored because of their model, issuer, signature, recipient or content
+    pub events_skipped: AtomicU64,
+    /// Events that failed while being processed
+    pub events_failed: AtomicU64,
+    pub materializations_created: AtomicU64,
+    pub materializations_updated: AtomicU64,
+}
+
+impl CalculatorMetrics {
+    pub(crate) fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
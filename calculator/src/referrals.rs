@@ -0,0 +1,289 @@
+use crate::materialization_cache::MaterializationCache;
+use ceramic_http_client::ceramic_event::StreamId;
+use models::PointAttestations;
+use std::collections::{HashMap, HashSet};
+
+pub const REFERRALS_CONTEXT: &str = "referrals";
+pub const DEFAULT_REFERRAL_DEPTH: usize = 3;
+pub const DEFAULT_REFERRAL_SHARE_PERCENT: i64 = 10;
+/// Deepest chain of referrals that shares points, keeping each level's weight within an `i128`
+pub const MAX_REFERRAL_DEPTH: usize = 10;
+
+/// Graph of holders linked by the `ref_id` of their attestations. Each holder has at most one
+/// referrer, and edges that would introduce a cycle are rejected.
+pub struct ReferralGraph {
+    max_depth: usize,
+    share_percent: i64,
+    referrers: HashMap<String, String>,
+    referees: HashMap<String, HashSet<String>>,
+    /// Points of each holder by the attestation stream they were attested in
+    points: HashMap<String, HashMap<String, i64>>,
+    attestation_holders: HashMap<String, String>,
+}
+
+impl ReferralGraph {
+    pub fn new(max_depth: usize, share_percent: i64) -> Self {
+        Self {
+            max_depth,
+            share_percent,
+            referrers: HashMap::default(),
+            referees: HashMap::default(),
+            points: HashMap::default(),
+            attestation_holders: HashMap::default(),
+        }
+    }
+
+    /// Record the attestations of a holder, returning the referrers whose awards may have changed
+    pub fn record(
+        &mut self,
+        holder: &str,
+        attestation: &PointAttestations,
+        attestation_stream_id: &StreamId,
+    ) -> Vec<String> {
+        self.attestation_holders
+            .insert(attestation_stream_id.to_string(), holder.to_string());
+        self.points.entry(holder.to_string()).or_default().insert(
+            attestation_stream_id.to_string(),
+            attestation.data.iter().map(|d| d.value).sum(),
+        );
+        if !self.referrers.contains_key(holder) {
+            let referrer = attestation
+                .data
+                .iter()
+                .filter_map(|d| d.ref_id.as_deref())
+                .find_map(|ref_id| self.resolve(ref_id));
+            if let Some(referrer) = referrer {
+                self.add_referral(holder, &referrer);
+            }
+        }
+        self.ancestors(holder)
+    }
+
+    fn resolve(&self, ref_id: &str) -> Option<String> {
+        if ref_id.starts_with("did:") {
+            Some(ref_id.to_string())
+        } else if let Some(holder) = self.attestation_holders.get(ref_id) {
+            Some(holder.clone())
+        } else {
+            tracing::debug!("Unknown referral {}", ref_id);
+            None
+        }
+    }
+
+    fn add_referral(&mut self, holder: &str, referrer: &str) {
+        if referrer == holder || self.is_ancestor(holder, referrer) {
+            tracing::warn!(
+                "Ignoring referral of {} by {}, would create a cycle",
+                holder,
+                referrer
+            );
+            return;
+        }
+        self.referrers
+            .insert(holder.to_string(), referrer.to_string());
+        self.referees
+            .entry(referrer.to_string())
+            .or_default()
+            .insert(holder.to_string());
+    }
+
+    /// Whether `ancestor` is reachable from `holder` by following referrers
+    fn is_ancestor(&self, ancestor: &str, holder: &str) -> bool {
+        let mut current = holder;
+        while let Some(referrer) = self.referrers.get(current) {
+            if referrer == ancestor {
+                return true;
+            }
+            current = referrer;
+        }
+        false
+    }
+
+    fn ancestors(&self, holder: &str) -> Vec<String> {
+        let mut ancestors = vec![];
+        let mut current = holder;
+        while ancestors.len() < self.max_depth {
+            match self.referrers.get(current) {
+                Some(referrer) => {
+                    ancestors.push(referrer.clone());
+                    current = referrer;
+                }
+                None => break,
+            }
+        }
+        ancestors
+    }
+
+    /// Points of a holder across all of their attestation streams
+    fn holder_points(&self, holder: &str) -> i64 {
+        self.points
+            .get(holder)
+            .map(|streams| streams.values().sum())
+            .unwrap_or_default()
+    }
+
+    /// Points awarded to a referrer from referees up to the configured depth. Each level's share
+    /// is kept as the fraction `share / scale`, so the award is exact and fails rather than
+    /// overflowing.
+    fn award(&self, referrer: &str) -> Result<i64, anyhow::Error> {
+        let overflow = || anyhow::anyhow!("Referral award for {} overflowed", referrer);
+        let mut total: i128 = 0;
+        let mut level: Vec<&String> = self
+            .referees
+            .get(referrer)
+            .map(|r| r.iter().collect())
+            .unwrap_or_default();
+        let mut share = i128::from(self.share_percent);
+        let mut scale: i128 = 100;
+        for _ in 0..self.max_depth {
+            if level.is_empty() {
+                break;
+            }
+            for referee in level.iter() {
+                let points = i128::from(self.holder_points(referee));
+                let award = points.checked_mul(share).ok_or_else(overflow)? / scale;
+                total = total.checked_add(award).ok_or_else(overflow)?;
+            }
+            share = share
+                .checked_mul(i128::from(self.share_percent))
+                .ok_or_else(overflow)?;
+            scale = scale.checked_mul(100).ok_or_else(overflow)?;
+            level = level
+                .into_iter()
+                .flat_map(|r| self.referees.get(r).into_iter().flatten())
+                .collect();
+        }
+        i64::try_from(total).map_err(|_| overflow())
+    }
+
+    pub async fn update(
+        &mut self,
+        cache: &mut MaterializationCache,
+        holder: &str,
+        attestation: &PointAttestations,
+        attestation_stream_id: &StreamId,
+    ) -> Result<(), anyhow::Error> {
+        for referrer in self.record(holder, attestation, attestation_stream_id) {
+            let value = self.award(&referrer)?;
+            match cache.get_points(&referrer, REFERRALS_CONTEXT).await? {
+                Some(mut existing) => {
+                    if existing.points.value == value {
+                        continue;
+                    }
+                    existing.points.value = value;
+                    tracing::info!(
+                        "Updating points for {}: {:?}",
+                        REFERRALS_CONTEXT,
+                        existing.points
+                    );
+                    cache.update_points(existing).await?;
+                }
+                None => {
+                    tracing::info!(
+                        "Creating points for referrer {} for {}",
+                        referrer,
+                        REFERRALS_CONTEXT
+                    );
+                    cache
+                        .create_points(&referrer, REFERRALS_CONTEXT, attestation_stream_id, value)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::PointAttestation;
+    use std::str::FromStr;
+
+    fn attestations(value: i64, ref_id: Option<&str>) -> PointAttestations {
+        PointAttestations {
+            issuer: "did:key:issuer".to_string(),
+            issuer_verification: String::default(),
+            data: vec![PointAttestation {
+                value,
+                context: "proof-of-data".to_string(),
+                timestamp: chrono::Utc::now(),
+                ref_id: ref_id.map(|s| s.to_string()),
+            }],
+        }
+    }
+
+    fn stream_id() -> StreamId {
+        StreamId::from_str("kjzl6kcym7w8y7nzgytqayf6aro12zt0mm01n6ydjomyvvklcspx9kr6gpbwd09")
+            .unwrap()
+    }
+
+    fn other_stream_id() -> StreamId {
+        StreamId::from_str("kjzl6kcym7w8y5v2l4l6xlmvz0ojl8yq4ffa2c0xlm5nbi1dfpm2n0q1vwl7d4r")
+            .unwrap()
+    }
+
+    #[test]
+    fn awards_share_of_referee_points_by_depth() {
+        let mut graph = ReferralGraph::new(2, 10);
+        graph.record("did:a", &attestations(0, None), &stream_id());
+        graph.record("did:b", &attestations(100, Some("did:a")), &stream_id());
+        let changed = graph.record("did:c", &attestations(1000, Some("did:b")), &stream_id());
+        assert_eq!(changed, vec!["did:b".to_string(), "did:a".to_string()]);
+        assert_eq!(graph.award("did:b").unwrap(), 100);
+        assert_eq!(graph.award("did:a").unwrap(), 10 + 10);
+    }
+
+    #[test]
+    fn awards_deep_referrals_without_overflow() {
+        let mut graph = ReferralGraph::new(MAX_REFERRAL_DEPTH, 100);
+        let holders: Vec<_> = (0..=MAX_REFERRAL_DEPTH)
+            .map(|i| format!("did:{}", i))
+            .collect();
+        graph.record(&holders[0], &attestations(0, None), &stream_id());
+        for pair in holders.windows(2) {
+            graph.record(&pair[1], &attestations(1000, Some(&pair[0])), &stream_id());
+        }
+        assert_eq!(
+            graph.award(&holders[0]).unwrap(),
+            1000 * MAX_REFERRAL_DEPTH as i64
+        );
+
+        let mut graph = ReferralGraph::new(MAX_REFERRAL_DEPTH, 100);
+        graph.record("did:a", &attestations(0, None), &stream_id());
+        graph.record(
+            "did:b",
+            &attestations(i64::MAX, Some("did:a")),
+            &stream_id(),
+        );
+        graph.record(
+            "did:c",
+            &attestations(i64::MAX, Some("did:b")),
+            &stream_id(),
+        );
+        assert!(graph.award("did:a").is_err());
+    }
+
+    #[test]
+    fn sums_points_across_attestation_streams() {
+        let mut graph = ReferralGraph::new(1, 10);
+        graph.record("did:a", &attestations(0, None), &stream_id());
+        graph.record("did:b", &attestations(100, Some("did:a")), &stream_id());
+        graph.record("did:b", &attestations(300, None), &other_stream_id());
+        assert_eq!(graph.award("did:a").unwrap(), 40);
+        // a new version of a stream replaces that stream's points only
+        graph.record("did:b", &attestations(200, None), &stream_id());
+        assert_eq!(graph.award("did:a").unwrap(), 50);
+    }
+
+    #[test]
+    fn rejects_referral_cycles() {
+        let mut graph = ReferralGraph::new(3, 10);
+        graph.record("did:a", &attestations(10, Some("did:b")), &stream_id());
+        graph.record("did:b", &attestations(10, Some("did:a")), &stream_id());
+        graph.record("did:c", &attestations(10, Some("did:c")), &stream_id());
+        assert_eq!(graph.referrers.get("did:a"), Some(&"did:b".to_string()));
+        assert!(!graph.referrers.contains_key("did:b"));
+        assert!(!graph.referrers.contains_key("did:c"));
+    }
+}
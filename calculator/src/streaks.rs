@@ -0,0 +1,186 @@
+use crate::materialization_cache::MaterializationCache;
+use ceramic_http_client::ceramic_event::StreamId;
+use chrono::{DateTime, Datelike, Utc};
+use models::PointAttestations;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreakPeriod {
+    Day,
+    Week,
+}
+
+impl StreakPeriod {
+    fn index(&self, timestamp: &DateTime<Utc>) -> i64 {
+        let days = timestamp.num_days_from_ce() as i64;
+        match self {
+            Self::Day => days,
+            Self::Week => (days - timestamp.weekday().num_days_from_monday() as i64) / 7,
+        }
+    }
+}
+
+impl FromStr for StreakPeriod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "day" | "daily" => Ok(Self::Day),
+            "week" | "weekly" => Ok(Self::Week),
+            _ => anyhow::bail!("Invalid streak period {}", s),
+        }
+    }
+}
+
+/// Tracks consecutive periods with at least one attestation in `context`
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreakRule {
+    pub context: String,
+    pub period: StreakPeriod,
+}
+
+impl StreakRule {
+    /// Parse a comma separated list of `context:period` rules, e.g. `proof-of-data:day,depin:week`
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, anyhow::Error> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let (context, period) = s
+                    .rsplit_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid streak rule {}", s))?;
+                Ok(Self {
+                    context: context.to_string(),
+                    period: StreakPeriod::from_str(period)?,
+                })
+            })
+            .collect()
+    }
+
+    fn current_context(&self) -> String {
+        format!("{}-current-streak", self.context)
+    }
+
+    fn longest_context(&self) -> String {
+        format!("{}-longest-streak", self.context)
+    }
+}
+
+/// Returns the current and longest streak of consecutive periods. The current streak is still
+/// alive if the latest period is either the one containing `now` or the one before it.
+fn streaks<'a>(
+    timestamps: impl Iterator<Item = &'a DateTime<Utc>>,
+    period: StreakPeriod,
+    now: &DateTime<Utc>,
+) -> (i64, i64) {
+    let periods: BTreeSet<_> = timestamps.map(|t| period.index(t)).collect();
+    let mut longest = 0;
+    let mut run = 0;
+    let mut last = None;
+    for p in periods.iter() {
+        run = match last {
+            Some(l) if l + 1 == *p => run + 1,
+            _ => 1,
+        };
+        longest = std::cmp::max(longest, run);
+        last = Some(*p);
+    }
+    let current = match last {
+        Some(l) if period.index(now) - l <= 1 => run,
+        _ => 0,
+    };
+    (current, longest)
+}
+
+/// Materialize a holder's streaks as of `now`, writing only the values that changed
+pub async fn streak_events(
+    cache: &mut MaterializationCache,
+    holder: &str,
+    attestation: &PointAttestations,
+    attestation_stream_id: &StreamId,
+    rule: &StreakRule,
+    now: &DateTime<Utc>,
+) -> Result<(), anyhow::Error> {
+    let timestamps = attestation
+        .data
+        .iter()
+        .filter(|d| d.context == rule.context)
+        .map(|d| &d.timestamp);
+    let (current, longest) = streaks(timestamps, rule.period, now);
+    for (context, value) in [
+        (rule.current_context(), current),
+        (rule.longest_context(), longest),
+    ] {
+        match cache.get_points(holder, &context).await? {
+            Some(mut existing) => {
+                if existing.points.value == value {
+                    continue;
+                }
+                existing.points.value = value;
+                tracing::info!("Updating points for {}: {:?}", context, existing.points);
+                cache.update_points(existing).await?;
+            }
+            None => {
+                tracing::info!("Creating points for holder {} for {}", holder, context);
+                cache
+                    .create_points(holder, &context, attestation_stream_id, value)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn day(d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, d, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn computes_daily_streaks() {
+        let timestamps = [day(1), day(2), day(2), day(3), day(4), day(7), day(8)];
+        assert_eq!(
+            streaks(timestamps.iter(), StreakPeriod::Day, &day(9)),
+            (2, 4)
+        );
+        assert_eq!(
+            streaks(timestamps.iter(), StreakPeriod::Day, &day(10)),
+            (0, 4)
+        );
+    }
+
+    #[test]
+    fn computes_weekly_streaks() {
+        // 2024-03-04 is a Monday
+        let timestamps = [day(3), day(4), day(10), day(11), day(25)];
+        assert_eq!(
+            streaks(timestamps.iter(), StreakPeriod::Week, &day(31)),
+            (1, 3)
+        );
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = StreakRule::parse_list("proof-of-data:day, depin:Week").unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                StreakRule {
+                    context: "proof-of-data".to_string(),
+                    period: StreakPeriod::Day,
+                },
+                StreakRule {
+                    context: "depin".to_string(),
+                    period: StreakPeriod::Week,
+                },
+            ]
+        );
+        assert!(StreakRule::parse_list("depin:month").is_err());
+    }
+}
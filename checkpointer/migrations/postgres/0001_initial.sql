@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS events
+(
+    seq         BIGSERIAL PRIMARY KEY,
+    id          TEXT UNIQUE NOT NULL,
+    client_id   TEXT        NOT NULL,
+    event       JSONB       NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS feed_positions
+(
+    feed        TEXT PRIMARY KEY NOT NULL,
+    position    TEXT             NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS registrations
+(
+    client_id   TEXT PRIMARY KEY NOT NULL,
+    filter      JSONB            NOT NULL,
+    created_at  BIGINT           NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS archived_events
+(
+    seq         BIGINT NOT NULL,
+    id          TEXT   NOT NULL,
+    client_id   TEXT   NOT NULL,
+    event       JSONB  NOT NULL,
+    archived_at BIGINT NOT NULL
+);
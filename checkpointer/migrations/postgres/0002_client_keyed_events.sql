@@ -0,0 +1,6 @@
+-- Key events by client so several clients can hold the same event, deduplicating within a client
+ALTER TABLE events DROP CONSTRAINT events_id_key;
+
+ALTER TABLE events ADD CONSTRAINT events_client_id_id_key UNIQUE (client_id, id);
+
+CREATE INDEX events_client_seq ON events (client_id, seq);
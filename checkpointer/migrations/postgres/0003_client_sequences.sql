@@ -0,0 +1,16 @@
+-- Sequences are assigned per client at ingest, continuing from the registration's last sequence
+ALTER TABLE registrations ADD COLUMN last_sequence BIGINT NOT NULL DEFAULT 0;
+
+UPDATE registrations SET last_sequence = COALESCE(
+    (SELECT MAX(seq) FROM events WHERE events.client_id = registrations.client_id), 0
+);
+
+ALTER TABLE events DROP CONSTRAINT events_pkey;
+
+ALTER TABLE events ALTER COLUMN seq DROP DEFAULT;
+
+DROP SEQUENCE events_seq_seq;
+
+DROP INDEX events_client_seq;
+
+ALTER TABLE events ADD PRIMARY KEY (client_id, seq);
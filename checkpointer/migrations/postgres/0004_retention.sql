@@ -0,0 +1,11 @@
+-- Events record when they were stored and their stream, so they can be expired and compacted
+ALTER TABLE events ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+
+ALTER TABLE events ADD COLUMN stream_id TEXT;
+
+-- the stream of stored events is only in their payload, so they keep no stream and are never compacted
+UPDATE events SET created_at = EXTRACT(EPOCH FROM now())::BIGINT;
+
+CREATE INDEX events_client_stream ON events (client_id, stream_id);
+
+ALTER TABLE registrations ADD COLUMN retention JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+-- Batchers are bound to the DID that created them. Existing batchers have no owner until an admin
+-- claims them.
+ALTER TABLE registrations ADD COLUMN owner TEXT;
@@ -0,0 +1,7 @@
+-- Events table as created by releases before versioned migrations
+CREATE TABLE IF NOT EXISTS events
+(
+    id          TEXT PRIMARY KEY NOT NULL,
+    client_id   TEXT             NOT NULL,
+    event       JSONB            NOT NULL
+);
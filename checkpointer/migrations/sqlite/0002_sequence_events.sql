@@ -0,0 +1,15 @@
+-- Give events a sequence so clients can acknowledge by cursor
+CREATE TABLE events_sequenced
+(
+    seq         INTEGER PRIMARY KEY AUTOINCREMENT,
+    id          TEXT UNIQUE NOT NULL,
+    client_id   TEXT        NOT NULL,
+    event       JSONB       NOT NULL
+);
+
+INSERT INTO events_sequenced (id, client_id, event)
+SELECT id, client_id, event FROM events ORDER BY rowid;
+
+DROP TABLE events;
+
+ALTER TABLE events_sequenced RENAME TO events;
@@ -0,0 +1,18 @@
+-- Key events by client so several clients can hold the same event, deduplicating within a client
+CREATE TABLE events_keyed
+(
+    seq         INTEGER PRIMARY KEY AUTOINCREMENT,
+    client_id   TEXT  NOT NULL,
+    id          TEXT  NOT NULL,
+    event       JSONB NOT NULL,
+    UNIQUE (client_id, id)
+);
+
+INSERT INTO events_keyed (seq, client_id, id, event)
+SELECT seq, client_id, id, event FROM events;
+
+DROP TABLE events;
+
+ALTER TABLE events_keyed RENAME TO events;
+
+CREATE INDEX events_client_seq ON events (client_id, seq);
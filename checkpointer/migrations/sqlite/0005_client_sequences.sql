@@ -0,0 +1,23 @@
+-- Sequences are assigned per client at ingest, continuing from the registration's last sequence
+ALTER TABLE registrations ADD COLUMN last_sequence INTEGER NOT NULL DEFAULT 0;
+
+UPDATE registrations SET last_sequence = COALESCE(
+    (SELECT MAX(seq) FROM events WHERE events.client_id = registrations.client_id), 0
+);
+
+CREATE TABLE events_by_client
+(
+    client_id   TEXT    NOT NULL,
+    seq         INTEGER NOT NULL,
+    id          TEXT    NOT NULL,
+    event       JSONB   NOT NULL,
+    PRIMARY KEY (client_id, seq),
+    UNIQUE (client_id, id)
+);
+
+INSERT INTO events_by_client (client_id, seq, id, event)
+SELECT client_id, seq, id, event FROM events;
+
+DROP TABLE events;
+
+ALTER TABLE events_by_client RENAME TO events;
@@ -0,0 +1,187 @@
+use crate::config::AuthSettings;
+use crate::errors::Error;
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use models::{Claims, API_AUDIENCE};
+use std::collections::HashSet;
+
+/// Allowed difference between the caller's clock and ours when checking when a token was issued
+const CLOCK_SKEW_SECONDS: i64 = 30;
+
+/// Authenticates requests carrying a bearer token, which is a [`Claims`] JWS that the caller's
+/// did:key issues to itself for [`API_AUDIENCE`]
+pub struct Authenticator {
+    admins: HashSet<String>,
+    max_token_lifetime: i64,
+}
+
+impl Authenticator {
+    pub fn new(settings: &AuthSettings) -> Self {
+        Self {
+            admins: settings.admin_dids.iter().cloned().collect(),
+            max_token_lifetime: settings.max_token_lifetime_seconds,
+        }
+    }
+
+    pub async fn authenticate(&self, token: &str) -> Result<Caller, Error> {
+        let claims = models::verify_claims_for(token, API_AUDIENCE, None)
+            .await
+            .map_err(|e| Error::Unauthorized(format!("Invalid token: {}", e)))?;
+        self.caller(&claims, chrono::Utc::now().timestamp())
+    }
+
+    /// The caller for verified claims, checking they are self issued and short lived
+    fn caller(&self, claims: &Claims, now: i64) -> Result<Caller, Error> {
+        if claims.sub != claims.iss {
+            return Err(Error::Unauthorized(
+                "Token must be issued by its subject".to_string(),
+            ));
+        }
+        if claims.iat > now + CLOCK_SKEW_SECONDS {
+            return Err(Error::Unauthorized(
+                "Token issued in the future".to_string(),
+            ));
+        }
+        if claims.exp - claims.iat > self.max_token_lifetime {
+            return Err(Error::Unauthorized(format!(
+                "Token lifetime exceeds {} seconds",
+                self.max_token_lifetime
+            )));
+        }
+        Ok(Caller {
+            admin: self.admins.contains(&claims.iss),
+            did: claims.iss.clone(),
+        })
+    }
+}
+
+/// The DID a request is authenticated as
+#[derive(Clone, Debug, PartialEq)]
+pub struct Caller {
+    pub did: String,
+    pub admin: bool,
+}
+
+impl Caller {
+    pub fn require_admin(&self) -> Result<(), Error> {
+        if self.admin {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!("{} is not an admin", self.did)))
+        }
+    }
+
+    /// Whether the caller may act for `did`. Admins may act for anyone.
+    pub fn can_act_for(&self, did: Option<&str>) -> bool {
+        self.admin || did == Some(self.did.as_str())
+    }
+
+    pub fn require_owner(&self, client_id: &str, owner: Option<&str>) -> Result<(), Error> {
+        if self.can_act_for(owner) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!(
+                "Batcher {} does not belong to {}",
+                client_id, self.did
+            )))
+        }
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let auth = req.app_data::<web::Data<Authenticator>>().cloned();
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|t| t.trim().to_string());
+        Box::pin(async move {
+            let auth = auth.ok_or_else(|| Error::custom("Authenticator not configured"))?;
+            let token =
+                token.ok_or_else(|| Error::Unauthorized("Missing bearer token".to_string()))?;
+            auth.authenticate(&token).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DID: &str = "did:key:caller";
+    const NOW: i64 = 1_700_000_000;
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthSettings {
+            admin_dids: vec!["did:key:admin".to_string()],
+            max_token_lifetime_seconds: 60,
+        })
+    }
+
+    fn claims(did: &str) -> Claims {
+        Claims {
+            iat: NOW,
+            exp: NOW + 60,
+            ..Claims::for_api(did, chrono::Duration::seconds(60))
+        }
+    }
+
+    #[test]
+    fn accepts_self_issued_short_lived_claims() {
+        let auth = authenticator();
+        let caller = auth.caller(&claims(DID), NOW).unwrap();
+        assert_eq!(caller.did, DID);
+        assert!(!caller.admin);
+        assert!(auth.caller(&claims("did:key:admin"), NOW).unwrap().admin);
+    }
+
+    #[test]
+    fn rejects_invalid_claims() {
+        let auth = authenticator();
+        let other_subject = Claims {
+            sub: "did:key:other".to_string(),
+            ..claims(DID)
+        };
+        let long_lived = Claims {
+            exp: NOW + 3600,
+            ..claims(DID)
+        };
+        let future = Claims {
+            iat: NOW + 3600,
+            exp: NOW + 3660,
+            ..claims(DID)
+        };
+        for claims in [other_subject, long_lived, future] {
+            assert!(matches!(
+                auth.caller(&claims, NOW),
+                Err(Error::Unauthorized(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn authorizes_owners_and_admins() {
+        let caller = Caller {
+            did: DID.to_string(),
+            admin: false,
+        };
+        assert!(caller.require_owner("client", Some(DID)).is_ok());
+        assert!(caller
+            .require_owner("client", Some("did:key:other"))
+            .is_err());
+        assert!(caller.require_owner("client", None).is_err());
+        assert!(caller.require_admin().is_err());
+        let admin = Caller {
+            did: "did:key:admin".to_string(),
+            admin: true,
+        };
+        assert!(admin.require_owner("client", Some(DID)).is_ok());
+        assert!(admin.require_owner("client", None).is_ok());
+        assert!(admin.require_admin().is_ok());
+    }
+}
@@ -1,223 +1,1139 @@
+use crate::auth::Caller;
+use crate::config::Settings;
 use crate::errors::Error;
-use crate::event_source::{EventSource, RunningEventSource};
-use crate::persistence::Persistence;
-use schema::Event;
+use crate::event_source::{EventSource, FeedEvent, RunningEventSource};
+use crate::metrics::metrics;
+use crate::persistence::{BatchLimit, Persistence, Registration};
+use schema::{
+    Batch, BatcherStatus, DroppedEvents, Event, EventFilter, FeedState, RetentionPolicy,
+    SequencedEvent,
+};
 use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
-use std::ops::DerefMut;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+use tracing::Instrument;
 use url::Url;
 
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_BATCH_EVENTS: usize = 1000;
+pub const DEFAULT_MAX_BUFFERED_EVENTS: usize = 10_000;
+pub const DEFAULT_RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(60);
+/// Longest configurable wait, keeping every request's deadline representable
+pub const MAX_WAIT_LIMIT: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct BatchCreationParameters {
     pub client_id: String,
+    /// Only events matching the filter are stored and delivered
+    #[serde(default)]
+    pub filter: EventFilter,
+    /// Limits on the events stored for the client while it is not acknowledging them
+    #[serde(default)]
+    pub retention: RetentionPolicy,
 }
 
-struct BatchCreationRequest {
-    params: BatchCreationParameters,
-    tx: tokio::sync::oneshot::Sender<Result<(), Error>>,
+#[derive(Clone, Debug)]
+pub struct BatcherOptions {
+    /// How long delivered events may go unacknowledged before they are redelivered
+    pub ack_timeout: Duration,
+    /// Most events returned in one batch, whatever limit is requested
+    pub max_batch_events: usize,
+    /// Most events held in memory for a client while they cannot be stored. Once reached the feed
+    /// is paused and replayed from its saved position after the buffer is stored.
+    pub max_buffered_events: usize,
+    /// How often stored events are checked against each client's retention policy
+    pub retention_interval: Duration,
+    /// Longest a request waits for events, whatever wait is requested
+    pub max_wait: Duration,
 }
 
-pub type EventResponder = tokio::sync::oneshot::Sender<Result<Vec<Event>, Error>>;
+impl Default for BatcherOptions {
+    fn default() -> Self {
+        Self {
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            max_batch_events: DEFAULT_MAX_BATCH_EVENTS,
+            max_buffered_events: DEFAULT_MAX_BUFFERED_EVENTS,
+            retention_interval: DEFAULT_RETENTION_INTERVAL,
+            max_wait: DEFAULT_MAX_WAIT,
+        }
+    }
+}
+
+pub type EventResponder = oneshot::Sender<Result<Batch, Error>>;
+
+/// Feed errors already reported to a reader. Each reader keeps its own count, so reading an error
+/// does not take it from polling consumers or other readers.
+#[derive(Clone, Default)]
+pub struct ReportedErrors(Arc<Mutex<Option<u64>>>);
+
+struct Read {
+    cursor: i64,
+    reported: ReportedErrors,
+}
 
 struct GetRequest {
-    limit: Option<usize>,
+    /// Read the events after a cursor without leasing them for acknowledgement
+    read: Option<Read>,
+    limit: BatchLimit,
+    deadline: Option<Instant>,
     tx: EventResponder,
 }
 
+enum Request {
+    Create {
+        params: BatchCreationParameters,
+        caller: Caller,
+        tx: oneshot::Sender<Result<(), Error>>,
+    },
+    Get {
+        client_id: String,
+        req: GetRequest,
+    },
+    Ack {
+        client_id: String,
+        cursor: i64,
+        tx: oneshot::Sender<Result<(), Error>>,
+    },
+    Delete {
+        client_id: String,
+        archive: bool,
+        tx: oneshot::Sender<Result<(), Error>>,
+    },
+    Status {
+        client_id: String,
+        tx: oneshot::Sender<Result<BatcherStatus, Error>>,
+    },
+    Owner {
+        client_id: String,
+        tx: oneshot::Sender<Result<Option<String>, Error>>,
+    },
+    List {
+        tx: oneshot::Sender<Result<Vec<BatcherStatus>, Error>>,
+    },
+    Shutdown {
+        tx: oneshot::Sender<()>,
+    },
+}
+
+type FeedResult = Result<FeedEvent, Error>;
+
+#[derive(Default)]
+struct Delivery {
+    /// Highest sequence handed to the client
+    delivered: i64,
+    /// Highest sequence ever polled or read by the client, which redelivery does not reset
+    handed_out: i64,
+    /// Highest sequence acknowledged by the client
+    acked: i64,
+    /// When unacknowledged events will be handed out again
+    redeliver_at: Option<Instant>,
+}
+
 #[derive(Default)]
 struct PendingResults {
+    /// Events yet to be stored, which take a sequence once they are
     events: Vec<Event>,
+    /// Last sequence taken by a stored event
+    sequence: i64,
+    /// Feed error not yet returned to a polling consumer
     error: Option<Error>,
+    /// Feed errors since the client started, and the latest of them, for readers
+    feed_errors: u64,
+    last_feed_error: Option<Error>,
+    delivery: Delivery,
+    last_event_at: Option<i64>,
+    last_error: Option<String>,
+    dropped: DroppedEvents,
+}
+
+struct Feed {
+    /// Stops the source
+    shutdown: watch::Sender<bool>,
+    /// Set once the feed is stopped, after which forwarded events are discarded
+    stopped: Arc<AtomicBool>,
+    connected: Arc<AtomicBool>,
+    join: tokio::task::JoinHandle<()>,
+    /// Forwards events from the source to the worker, until taken to drain them on shutdown
+    forward: Option<tokio::task::JoinHandle<()>>,
+}
+
+struct Client {
+    created_at: i64,
+    owner: Option<String>,
+    filter: EventFilter,
+    retention: RetentionPolicy,
 }
 
 #[derive(Clone)]
 pub struct Batcher {
-    db: Arc<dyn Persistence + Send + Sync>,
-    ceramic_url: Url,
-    stream_create_requests: Arc<tokio::sync::Mutex<HashMap<String, BatchCreationRequest>>>,
-    stream_get_requests: Arc<tokio::sync::Mutex<HashMap<String, GetRequest>>>,
-    stream_delete_requests: Arc<tokio::sync::Mutex<HashSet<String>>>,
+    tx: mpsc::Sender<Request>,
+    max_wait: Duration,
 }
 
 impl Batcher {
-    pub fn new(db: Arc<dyn Persistence + Send + Sync>) -> Result<Self, Error> {
-        let u = std::env::var("CERAMIC_URL").expect("CERAMIC_URL not set in environment");
-        let u = Url::parse(&u)?;
-        let u = u.join("/api/v0/feed/aggregation/documents")?;
-        Ok(Self::new_with_url(db, u))
+    pub fn new(db: Arc<dyn Persistence + Send + Sync>, settings: &Settings) -> Result<Self, Error> {
+        let u = settings
+            .ceramic_url
+            .join("/api/v0/feed/aggregation/documents")?;
+        Ok(Self::new_with_options(
+            db,
+            u,
+            BatcherOptions::from(&settings.batcher),
+        ))
     }
 
-    pub fn new_with_url(db: Arc<dyn Persistence + Send + Sync>, ceramic_url: Url) -> Self {
-        let me = Self {
+    pub fn new_with_options(
+        db: Arc<dyn Persistence + Send + Sync>,
+        ceramic_url: Url,
+        options: BatcherOptions,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(100);
+        let (events_tx, events_rx) = mpsc::channel(1000);
+        let max_wait = options.max_wait;
+        let worker = Worker {
             db,
             ceramic_url,
-            stream_create_requests: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
-            stream_get_requests: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
-            stream_delete_requests: Arc::new(tokio::sync::Mutex::new(HashSet::new())),
+            options,
+            events_tx,
+            feed: None,
+            position: None,
+            clients: HashMap::default(),
+            outstanding_events: HashMap::default(),
+            waiting: HashMap::default(),
         };
-        tokio::spawn(me.clone().run());
-        me
+        tokio::spawn(worker.run(rx, events_rx));
+        Self { tx, max_wait }
     }
 
-    pub async fn create_batcher(&self, params: BatchCreationParameters) -> Result<(), Error> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        self.stream_create_requests.lock().await.insert(
-            params.client_id.clone(),
-            BatchCreationRequest { params, tx },
-        );
+    async fn send(&self, req: Request) -> Result<(), Error> {
+        self.tx.send(req).await.map_err(|_| Error::NotRunning)
+    }
 
+    /// Create a batcher bound to `caller`, or update the filter and retention of an existing
+    /// batcher that `caller` holds. Batchers registered without an owner may only be claimed by an
+    /// admin.
+    pub async fn create_batcher(
+        &self,
+        params: BatchCreationParameters,
+        caller: &Caller,
+    ) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::Create {
+            params,
+            caller: caller.clone(),
+            tx,
+        })
+        .await?;
         rx.await.map_err(Error::Recv)?
     }
 
+    /// Get the next batch of events for a client. If there are no events and `wait` is provided,
+    /// the request is held until events arrive or `wait`, capped at the configured maximum,
+    /// elapses. Events are handed out again if they are not acknowledged within the ack timeout.
     pub async fn get_batch(
         &self,
         client_id: &str,
-        limit: Option<usize>,
-    ) -> Result<Vec<Event>, Error> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        self.stream_get_requests
-            .lock()
-            .await
-            .insert(client_id.to_string(), GetRequest { limit, tx });
+        limit: BatchLimit,
+        wait: Option<Duration>,
+    ) -> Result<Batch, Error> {
+        self.request_batch(client_id, None, limit, wait).await
+    }
+
+    /// Read the stored events after `cursor`, waiting as in `get_batch`. Unlike `get_batch`,
+    /// reads do not affect which events are handed out or redelivered to polling consumers. Feed
+    /// errors after the reader's first read are returned once, as counted in `reported`.
+    pub async fn read_batch(
+        &self,
+        client_id: &str,
+        cursor: i64,
+        reported: &ReportedErrors,
+        limit: BatchLimit,
+        wait: Option<Duration>,
+    ) -> Result<Batch, Error> {
+        let read = Read {
+            cursor,
+            reported: reported.clone(),
+        };
+        self.request_batch(client_id, Some(read), limit, wait).await
+    }
+
+    async fn request_batch(
+        &self,
+        client_id: &str,
+        read: Option<Read>,
+        limit: BatchLimit,
+        wait: Option<Duration>,
+    ) -> Result<Batch, Error> {
+        let (tx, rx) = oneshot::channel();
+        let req = GetRequest {
+            read,
+            limit,
+            deadline: wait.map(|w| Instant::now() + w.min(self.max_wait)),
+            tx,
+        };
+        self.send(Request::Get {
+            client_id: client_id.to_string(),
+            req,
+        })
+        .await?;
         match rx.await {
             Err(_) => Err(Error::custom("Failed to receive batch")),
             Ok(r) => r,
         }
     }
 
-    pub async fn run(self) {
-        let mut streams: HashMap<String, RunningEventSource> = HashMap::default();
-        let mut outstanding_events: HashMap<String, PendingResults> = HashMap::default();
-        let db = Arc::clone(&self.db);
+    /// Acknowledge every event up to and including `cursor`, removing them from storage
+    pub async fn ack(&self, client_id: &str, cursor: i64) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::Ack {
+            client_id: client_id.to_string(),
+            cursor,
+            tx,
+        })
+        .await?;
+        rx.await.map_err(Error::Recv)?
+    }
+
+    /// Stop delivering events to a client and remove its registration and stored events. If
+    /// `archive` is set, stored events are kept in the archive rather than discarded.
+    pub async fn delete_batcher(&self, client_id: &str, archive: bool) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::Delete {
+            client_id: client_id.to_string(),
+            archive,
+            tx,
+        })
+        .await?;
+        rx.await.map_err(Error::Recv)?
+    }
+
+    pub async fn status(&self, client_id: &str) -> Result<BatcherStatus, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::Status {
+            client_id: client_id.to_string(),
+            tx,
+        })
+        .await?;
+        rx.await.map_err(Error::Recv)?
+    }
+
+    /// The DID a client's batcher is bound to, read from its registration without touching storage
+    pub async fn owner(&self, client_id: &str) -> Result<Option<String>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::Owner {
+            client_id: client_id.to_string(),
+            tx,
+        })
+        .await?;
+        rx.await.map_err(Error::Recv)?
+    }
+
+    pub async fn list(&self) -> Result<Vec<BatcherStatus>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::List { tx }).await?;
+        rx.await.map_err(Error::Recv)?
+    }
+
+    /// Stop the feed and store the events already received from it, after which the batcher
+    /// rejects requests. Events that still cannot be stored are replayed from the saved feed
+    /// position on the next start.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::Shutdown { tx }).await?;
+        rx.await.map_err(Error::Recv)
+    }
+}
+
+/// Name the shared feed is logged and reported under
+const FEED_NAME: &str = "batcher";
+
+struct Worker {
+    db: Arc<dyn Persistence + Send + Sync>,
+    ceramic_url: Url,
+    options: BatcherOptions,
+    events_tx: mpsc::Sender<FeedResult>,
+    /// Upstream feed shared by every client, running while any client is registered
+    feed: Option<Feed>,
+    /// Feed position to record once every client has persisted its events up to it
+    position: Option<String>,
+    clients: HashMap<String, Client>,
+    outstanding_events: HashMap<String, PendingResults>,
+    waiting: HashMap<String, Vec<GetRequest>>,
+}
+
+impl Worker {
+    async fn run(
+        mut self,
+        mut requests: mpsc::Receiver<Request>,
+        mut events: mpsc::Receiver<FeedResult>,
+    ) {
+        self.restore().await;
+        let mut retry = tokio::time::interval(Duration::from_secs(1));
+        let mut retention = tokio::time::interval(self.options.retention_interval);
         loop {
-            let mut streams_to_process = std::mem::take(&mut streams);
-            for (k, mut batcher) in streams_to_process.drain() {
-                let entry = outstanding_events.entry(k.clone()).or_default();
-                let existing = match db.get_events(&k).await {
-                    Ok(e) => e,
-                    Err(e) => {
-                        tracing::warn!("Failed to get events: {:?}", e);
-                        vec![]
+            let deadline = self
+                .waiting
+                .values()
+                .flatten()
+                .filter_map(|req| req.deadline)
+                .min();
+            let expire = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now));
+            tokio::select! {
+                req = requests.recv() => match req {
+                    Some(Request::Create { params, caller, tx }) => {
+                        let res = self.create(params, caller).await;
+                        if tx.send(res).is_err() {
+                            tracing::debug!("Failed to send create result to client");
+                        }
                     }
-                };
-                if !existing.is_empty() {
-                    tracing::trace!("Restoring {} events", existing.len());
-                    entry.events.extend(existing);
-                }
-
-                loop {
-                    match batcher.rx.try_recv() {
-                        Ok(Ok(event)) => {
-                            entry.events.push(event);
+                    Some(Request::Get { client_id, req }) => self.get(&client_id, req).await,
+                    Some(Request::Ack { client_id, cursor, tx }) => {
+                        let res = self.ack(&client_id, cursor).await;
+                        if tx.send(res).is_err() {
+                            tracing::debug!("Failed to send ack result to client");
+                        }
+                    }
+                    Some(Request::Delete { client_id, archive, tx }) => {
+                        let res = self.delete(&client_id, archive).await;
+                        if tx.send(res).is_err() {
+                            tracing::debug!("Failed to send delete result to client");
                         }
-                        Ok(Err(e)) => {
-                            entry.error = Some(e);
-                            break;
+                    }
+                    Some(Request::Status { client_id, tx }) => {
+                        let res = self.status(&client_id).await;
+                        if tx.send(res).is_err() {
+                            tracing::debug!("Failed to send status to client");
                         }
-                        Err(e) => {
-                            if let tokio::sync::mpsc::error::TryRecvError::Empty = e {
-                                streams.insert(k, batcher);
-                            }
-                            break;
+                    }
+                    Some(Request::Owner { client_id, tx }) => {
+                        let res = self
+                            .clients
+                            .get(&client_id)
+                            .map(|client| client.owner.clone())
+                            .ok_or(Error::NotFound(client_id));
+                        if tx.send(res).is_err() {
+                            tracing::debug!("Failed to send owner to client");
                         }
                     }
-                }
-            }
-            let create_requests = {
-                let mut m = self.stream_create_requests.lock().await;
-                std::mem::take(m.deref_mut())
-            };
-            for (client_id, req) in create_requests.into_iter() {
-                let es = EventSource::new(&req.params.client_id, &self.ceramic_url);
-                let running = es.run();
-                let events = db.get_events(&client_id).await.unwrap();
-                outstanding_events.insert(
-                    client_id.clone(),
-                    PendingResults {
-                        events,
-                        error: None,
-                    },
-                );
-                streams.insert(client_id, running);
-                req.tx.send(Ok(())).unwrap();
-            }
-            let get_requests = {
-                let mut m = self.stream_get_requests.lock().await;
-                std::mem::take(m.deref_mut())
-            };
-            for (client_id, req) in get_requests.into_iter() {
-                if let Some(mut results) = outstanding_events.remove(&client_id) {
-                    if results.events.is_empty() {
-                        if results.error.is_some() {
-                            let err = results.error.take().unwrap();
-                            if req.tx.send(Err(err)).is_err() {
-                                tracing::debug!("Failed to send error to client");
-                            }
-                        } else if req.tx.send(Ok(Vec::new())).is_err() {
-                            tracing::debug!("Failed to send empty results to client");
+                    Some(Request::List { tx }) => {
+                        let res = self.list().await;
+                        if tx.send(res).is_err() {
+                            tracing::debug!("Failed to send batcher list to client");
                         }
-                    } else {
-                        let split_idx = std::cmp::min(
-                            results.events.len(),
-                            req.limit.unwrap_or(usize::max_value()),
-                        );
-                        let mut rem = results.events.split_off(split_idx);
-                        std::mem::swap(&mut results.events, &mut rem);
-                        if req.tx.send(Ok(rem)).is_err() {
-                            tracing::debug!("Failed to send results to client");
+                    }
+                    Some(Request::Shutdown { tx }) => {
+                        self.shutdown(&mut events).await;
+                        if tx.send(()).is_err() {
+                            tracing::debug!("Failed to send shutdown result to client");
                         }
+                        break;
                     }
-                } else if req.tx.send(Err(Error::NotFound(client_id))).is_err() {
-                    tracing::debug!("Failed to send not found to client");
+                    None => break,
+                },
+                Some(event) = events.recv() => {
+                    self.receive(event).await;
+                }
+                _ = expire, if deadline.is_some() => {
+                    self.expire_waiting().await;
+                }
+                _ = retry.tick(), if self.has_unpersisted() || self.is_paused() => {
+                    let clients: Vec<_> = self.outstanding_events.keys().cloned().collect();
+                    for client_id in clients {
+                        self.persist(&client_id).await;
+                    }
+                    self.save_position().await;
+                    self.resume_feed().await;
+                }
+                _ = retention.tick() => {
+                    self.apply_retention().await;
                 }
             }
-            let mut delete_requests = {
-                let mut m = self.stream_delete_requests.lock().await;
-                std::mem::take(m.deref_mut())
-            };
-            for client_id in delete_requests.drain() {
-                if let Some(s) = streams.get(&client_id) {
-                    s.shutdown.store(true, Ordering::Relaxed);
+        }
+        self.stop_feed().await;
+    }
+
+    /// Start every batcher registered before the last shutdown
+    async fn restore(&mut self) {
+        let registrations = match self.db.get_registrations().await {
+            Ok(registrations) => registrations,
+            Err(e) => {
+                tracing::error!("Failed to load batcher registrations: {:?}", e);
+                return;
+            }
+        };
+        for registration in registrations {
+            let client_id = registration.client_id.clone();
+            tracing::info!("Restoring batcher {}", client_id);
+            if let Err(e) = self.start(registration).await {
+                tracing::error!("Failed to restore batcher {}: {:?}", client_id, e);
+            }
+        }
+    }
+
+    async fn create(
+        &mut self,
+        params: BatchCreationParameters,
+        caller: Caller,
+    ) -> Result<(), Error> {
+        let client_id = params.client_id;
+        let owner = caller.did;
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            tracing::debug!("Batcher {} already exists", client_id);
+            match &client.owner {
+                Some(o) if *o != owner => {
+                    return Err(Error::Forbidden(format!(
+                        "Batcher {} belongs to another DID",
+                        client_id
+                    )));
+                }
+                None if !caller.admin => {
+                    return Err(Error::Forbidden(format!(
+                        "Batcher {} has no owner and may only be claimed by an admin",
+                        client_id
+                    )));
+                }
+                _ => {}
+            }
+            if client.owner.is_none()
+                || client.filter != params.filter
+                || client.retention != params.retention
+            {
+                tracing::info!("Updating registration for {}", client_id);
+                // only the filter, retention and a missing owner of a registration are updated
+                self.db
+                    .add_registration(&Registration {
+                        client_id,
+                        filter: params.filter.clone(),
+                        retention: params.retention.clone(),
+                        created_at: client.created_at,
+                        last_sequence: 0,
+                        owner: Some(owner.clone()),
+                    })
+                    .await?;
+                client.filter = params.filter;
+                client.retention = params.retention;
+                client.owner = Some(owner);
+            }
+            return Ok(());
+        }
+        let registration = Registration {
+            client_id,
+            filter: params.filter,
+            retention: params.retention,
+            created_at: chrono::Utc::now().timestamp(),
+            last_sequence: 0,
+            owner: Some(owner),
+        };
+        self.db.add_registration(&registration).await?;
+        self.start(registration).await
+    }
+
+    async fn start(&mut self, registration: Registration) -> Result<(), Error> {
+        let Registration {
+            client_id,
+            filter,
+            retention,
+            created_at,
+            last_sequence,
+            owner,
+        } = registration;
+        self.start_feed().await?;
+        // continue numbering after the last event stored for the client
+        self.outstanding_events
+            .entry(client_id.clone())
+            .or_default()
+            .sequence = last_sequence;
+        self.clients.insert(
+            client_id,
+            Client {
+                created_at,
+                owner,
+                filter,
+                retention,
+            },
+        );
+        Ok(())
+    }
+
+    /// Connect to the upstream feed if it is not already running
+    async fn start_feed(&mut self) -> Result<(), Error> {
+        if self.feed.is_some() {
+            return Ok(());
+        }
+        let position = self.db.get_feed_position(self.ceramic_url.as_str()).await?;
+        if let Some(position) = position.as_ref() {
+            tracing::info!("Resuming feed from {}", position);
+        }
+        let RunningEventSource {
+            shutdown,
+            connected,
+            mut rx,
+            join,
+        } = EventSource::new(FEED_NAME, &self.ceramic_url, position).run();
+        let tx = self.events_tx.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let forwarding = stopped.clone();
+        let forward = tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if forwarding.load(Ordering::Relaxed) || tx.send(event).await.is_err() {
+                    return;
+                }
+            }
+        });
+        self.feed = Some(Feed {
+            shutdown,
+            stopped,
+            connected,
+            join,
+            forward: Some(forward),
+        });
+        Ok(())
+    }
+
+    async fn stop_feed(&mut self) {
+        if let Some(feed) = self.feed.take() {
+            feed.stopped.store(true, Ordering::Relaxed);
+            // the source may already have ended, leaving no one to receive the signal
+            let _ = feed.shutdown.send(true);
+            if let Err(e) = feed.join.await {
+                tracing::warn!("Event source failed: {:?}", e);
+            }
+        }
+    }
+
+    /// Stop the source, then store the events it already queued along with any buffered events
+    async fn shutdown(&mut self, events: &mut mpsc::Receiver<FeedResult>) {
+        tracing::info!("Shutting down batcher");
+        let forward = match self.feed.as_mut() {
+            Some(feed) => {
+                // ending the source closes its channel, so forwarding ends once it is drained
+                let _ = feed.shutdown.send(true);
+                if let Err(e) = (&mut feed.join).await {
+                    tracing::warn!("Event source failed: {:?}", e);
+                }
+                feed.forward.take()
+            }
+            None => None,
+        };
+        if let Some(mut forward) = forward {
+            loop {
+                tokio::select! {
+                    Some(event) = events.recv() => self.receive(event).await,
+                    _ = &mut forward => break,
                 }
             }
-            for (client_id, results) in outstanding_events.iter_mut() {
-                let events = std::mem::take(&mut results.events);
-                if !events.is_empty() {
-                    tracing::trace!("Saving {} events", events.len());
+        }
+        while let Ok(event) = events.try_recv() {
+            self.receive(event).await;
+        }
+        let clients: Vec<_> = self.outstanding_events.keys().cloned().collect();
+        for client_id in clients {
+            self.persist(&client_id).await;
+        }
+        self.save_position().await;
+        let unpersisted: usize = self
+            .outstanding_events
+            .values()
+            .map(|results| results.events.len())
+            .sum();
+        if unpersisted > 0 {
+            tracing::warn!(
+                "{} events were not stored and will be replayed on restart",
+                unpersisted
+            );
+        }
+        for (client_id, reqs) in self.waiting.drain() {
+            for req in reqs {
+                if req.tx.send(Ok(Batch::default())).is_err() {
+                    tracing::debug!("Failed to send empty batch to {}", client_id);
+                }
+            }
+        }
+    }
+
+    /// Whether the feed was stopped to bound buffered events, rather than for lack of clients
+    fn is_paused(&self) -> bool {
+        self.feed.is_none() && !self.clients.is_empty()
+    }
+
+    /// Restart a paused feed from its saved position once every buffered event is stored
+    async fn resume_feed(&mut self) {
+        if !self.is_paused() || self.has_unpersisted() {
+            return;
+        }
+        tracing::info!("Resuming feed");
+        if let Err(e) = self.start_feed().await {
+            tracing::warn!("Failed to resume feed: {:?}", e);
+        }
+    }
+
+    /// Fan an event from the feed out to every client whose filter it matches
+    async fn receive(&mut self, event: FeedResult) {
+        if self.feed.is_none() {
+            // queued before the feed was stopped, and replayed if it is resumed
+            return;
+        }
+        let event = match event {
+            Ok(FeedEvent { position, event }) => {
+                if position.is_some() {
+                    self.position = position;
+                }
+                event
+            }
+            Err(e) => {
+                tracing::warn!("Feed error: {}", e);
+                let clients: Vec<_> = self.clients.keys().cloned().collect();
+                for client_id in clients {
+                    if let Some(results) = self.outstanding_events.get_mut(&client_id) {
+                        results.last_error = Some(e.to_string());
+                        results.error = Some(client_error(&e));
+                        results.feed_errors += 1;
+                        results.last_feed_error = Some(client_error(&e));
+                    }
+                    self.wake(&client_id).await;
+                }
+                return;
+            }
+        };
+        let now = chrono::Utc::now().timestamp();
+        let mut received = vec![];
+        let mut overflowed = false;
+        for (client_id, client) in self.clients.iter() {
+            if !client.filter.matches(&event) {
+                continue;
+            }
+            let results = self
+                .outstanding_events
+                .entry(client_id.clone())
+                .or_default();
+            if results.events.len() >= self.options.max_buffered_events {
+                tracing::warn!("Too many buffered events for {}", client_id);
+                results.last_error = Some("Too many buffered events, feed paused".to_string());
+                overflowed = true;
+                continue;
+            }
+            results.last_event_at = Some(now);
+            if !results.events.is_empty() {
+                results.events.push(event.clone());
+            } else if let Err(event) =
+                add_event(self.db.as_ref(), client_id, results, event.clone()).await
+            {
+                results.events.push(event);
+            }
+            metrics()
+                .events_received
+                .with_label_values(&[client_id])
+                .inc();
+            metrics()
+                .buffered_events
+                .with_label_values(&[client_id])
+                .set(results.events.len() as i64);
+            received.push(client_id.clone());
+        }
+        if received.is_empty() {
+            tracing::trace!("Skipping event {}", event.commit_id);
+        }
+        if overflowed {
+            // the dropped event is after the saved position, so it is replayed on resume
+            self.position = None;
+            self.stop_feed().await;
+        }
+        self.save_position().await;
+        for client_id in received {
+            self.wake(&client_id).await;
+        }
+    }
+
+    /// Re-run any get requests waiting on a client
+    async fn wake(&mut self, client_id: &str) {
+        for req in self.waiting.remove(client_id).unwrap_or_default() {
+            self.get(client_id, req).await;
+        }
+    }
+
+    /// Respond to a get request, or hold it if there are no events and its deadline has not passed
+    async fn get(&mut self, client_id: &str, req: GetRequest) {
+        if !self.outstanding_events.contains_key(client_id) {
+            if req
+                .tx
+                .send(Err(Error::NotFound(client_id.to_string())))
+                .is_err()
+            {
+                tracing::debug!("Failed to send not found to client");
+            }
+            return;
+        }
+        let limit = BatchLimit {
+            max_events: Some(
+                req.limit
+                    .max_events
+                    .map_or(self.options.max_batch_events, |n| {
+                        n.min(self.options.max_batch_events)
+                    }),
+            ),
+            ..req.limit
+        };
+        let res = match req.read.as_ref() {
+            Some(read) => self.read_events(client_id, read, limit).await,
+            None => self.take_events(client_id, limit).await,
+        };
+        match res {
+            Ok(batch)
+                if batch.events.is_empty() && req.deadline.is_some_and(|d| d > Instant::now()) =>
+            {
+                self.waiting
+                    .entry(client_id.to_string())
+                    .or_default()
+                    .push(req);
+            }
+            res => {
+                if req.tx.send(res).is_err() {
+                    tracing::debug!("Failed to send results to client");
+                }
+            }
+        }
+    }
+
+    async fn take_events(&mut self, client_id: &str, limit: BatchLimit) -> Result<Batch, Error> {
+        let results = self
+            .outstanding_events
+            .entry(client_id.to_string())
+            .or_default();
+        let now = Instant::now();
+        if results.delivery.redeliver_at.is_some_and(|t| t <= now) {
+            tracing::debug!("Redelivering unacknowledged events for {}", client_id);
+            results.delivery.delivered = results.delivery.acked;
+            results.delivery.redeliver_at = None;
+        }
+        let timer = metrics().get_batch_seconds.start_timer();
+        let events = self
+            .db
+            .get_events(client_id, results.delivery.delivered, limit)
+            .await;
+        timer.observe_duration();
+        let events = events?;
+        if events.is_empty() {
+            return match results.error.take() {
+                Some(err) => Err(err),
+                None => Ok(Batch::default()),
+            };
+        }
+        let cursor = events.last().map(|ev| ev.sequence);
+        if let Some(cursor) = cursor {
+            results.delivery.delivered = cursor;
+            results.delivery.handed_out = std::cmp::max(results.delivery.handed_out, cursor);
+            results.delivery.redeliver_at = Some(now + self.options.ack_timeout);
+        }
+        trace_delivery(client_id, &events);
+        Ok(Batch { events, cursor })
+    }
+
+    async fn read_events(
+        &mut self,
+        client_id: &str,
+        read: &Read,
+        limit: BatchLimit,
+    ) -> Result<Batch, Error> {
+        let events = self.db.get_events(client_id, read.cursor, limit).await?;
+        let results = self
+            .outstanding_events
+            .entry(client_id.to_string())
+            .or_default();
+        let mut reported = read.reported.0.lock().unwrap();
+        // errors before the reader's first read are not reported to it
+        let seen = *reported.get_or_insert(results.feed_errors);
+        if events.is_empty() {
+            return match results.last_feed_error.as_ref() {
+                Some(err) if seen < results.feed_errors => {
+                    *reported = Some(results.feed_errors);
+                    Err(client_error(err))
                 }
-                let mut unpersisted_events = vec![];
-                for event in events {
-                    if let Err(e) = db.add_event(client_id, &event).await {
-                        tracing::warn!("Failed to persist event: {:?}", e);
-                        unpersisted_events.push(event);
+                _ => Ok(Batch::default()),
+            };
+        }
+        let cursor = events.last().map(|ev| ev.sequence);
+        if let Some(cursor) = cursor {
+            results.delivery.handed_out = std::cmp::max(results.delivery.handed_out, cursor);
+        }
+        trace_delivery(client_id, &events);
+        Ok(Batch { events, cursor })
+    }
+
+    async fn ack(&mut self, client_id: &str, cursor: i64) -> Result<(), Error> {
+        let results = self
+            .outstanding_events
+            .get_mut(client_id)
+            .ok_or_else(|| Error::NotFound(client_id.to_string()))?;
+        if cursor > results.delivery.handed_out {
+            return Err(Error::BadRequest(format!(
+                "Cursor {} is past the last delivered event {}",
+                cursor, results.delivery.handed_out
+            )));
+        }
+        self.db.ack_events(client_id, cursor).await?;
+        let delivery = &mut results.delivery;
+        delivery.acked = std::cmp::max(delivery.acked, cursor);
+        if delivery.acked >= delivery.delivered {
+            delivery.delivered = delivery.acked;
+            delivery.redeliver_at = None;
+        }
+        Ok(())
+    }
+
+    async fn delete(&mut self, client_id: &str, archive: bool) -> Result<(), Error> {
+        self.clients
+            .remove(client_id)
+            .ok_or_else(|| Error::NotFound(client_id.to_string()))?;
+        tracing::info!("Deleting batcher {}", client_id);
+        if self.clients.is_empty() {
+            self.stop_feed().await;
+        }
+        self.outstanding_events.remove(client_id);
+        metrics().remove_client(client_id);
+        for req in self.waiting.remove(client_id).unwrap_or_default() {
+            if req
+                .tx
+                .send(Err(Error::NotFound(client_id.to_string())))
+                .is_err()
+            {
+                tracing::debug!("Failed to send not found to client");
+            }
+        }
+        self.db.delete_client(client_id, archive).await?;
+        // the client's unpersisted events may have been holding back the feed position
+        self.save_position().await;
+        Ok(())
+    }
+
+    async fn status(&self, client_id: &str) -> Result<BatcherStatus, Error> {
+        let (client, results) = self
+            .clients
+            .get(client_id)
+            .zip(self.outstanding_events.get(client_id))
+            .ok_or_else(|| Error::NotFound(client_id.to_string()))?;
+        let connected = self
+            .feed
+            .as_ref()
+            .is_some_and(|feed| feed.connected.load(Ordering::Relaxed));
+        let feed_state = if connected {
+            FeedState::Connected
+        } else {
+            FeedState::Reconnecting
+        };
+        Ok(BatcherStatus {
+            client_id: client_id.to_string(),
+            owner: client.owner.clone(),
+            created_at: client.created_at,
+            feed_state,
+            buffered_events: results.events.len(),
+            persisted_events: self.db.count_events(client_id).await?,
+            last_event_at: results.last_event_at,
+            last_error: results.last_error.clone(),
+            dropped_events: results.dropped,
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<BatcherStatus>, Error> {
+        let mut clients: Vec<_> = self.clients.keys().collect();
+        clients.sort();
+        let mut statuses = Vec::with_capacity(clients.len());
+        for client_id in clients {
+            statuses.push(self.status(client_id).await?);
+        }
+        Ok(statuses)
+    }
+
+    /// Drop stored events outside each client's retention policy
+    async fn apply_retention(&mut self) {
+        let now = chrono::Utc::now().timestamp();
+        for (client_id, client) in self.clients.iter() {
+            if client.retention == RetentionPolicy::default() {
+                continue;
+            }
+            match self
+                .db
+                .apply_retention(client_id, &client.retention, now)
+                .await
+            {
+                Ok(dropped) => {
+                    if dropped.total() > 0 {
+                        tracing::info!("Dropped events for {}: {:?}", client_id, dropped);
                     }
+                    metrics().record_dropped(client_id, &dropped);
+                    if let Some(results) = self.outstanding_events.get_mut(client_id) {
+                        results.dropped += dropped;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to apply retention for {}: {:?}", client_id, e);
+                    metrics()
+                        .persistence_failures
+                        .with_label_values(&["apply_retention"])
+                        .inc();
+                }
+            }
+        }
+    }
+
+    async fn expire_waiting(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .waiting
+            .iter()
+            .filter(|(_, reqs)| reqs.iter().any(|r| r.deadline.is_none_or(|d| d <= now)))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in expired {
+            for req in self.waiting.remove(&client_id).unwrap_or_default() {
+                self.get(&client_id, req).await;
+            }
+        }
+    }
+
+    fn has_unpersisted(&self) -> bool {
+        self.position.is_some()
+            || self
+                .outstanding_events
+                .values()
+                .any(|results| !results.events.is_empty())
+    }
+
+    async fn persist(&mut self, client_id: &str) {
+        if let Some(results) = self.outstanding_events.get_mut(client_id) {
+            let events = std::mem::take(&mut results.events);
+            if !events.is_empty() {
+                tracing::trace!("Saving {} events", events.len());
+            }
+            for event in events {
+                // keep later events pending so they are stored in feed order
+                if !results.events.is_empty() {
+                    results.events.push(event);
+                } else if let Err(event) =
+                    add_event(self.db.as_ref(), client_id, results, event).await
+                {
+                    results.events.push(event);
                 }
-                results.events = unpersisted_events;
             }
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            metrics()
+                .buffered_events
+                .with_label_values(&[client_id])
+                .set(results.events.len() as i64);
+        }
+    }
+
+    /// Record the feed position once every client has persisted all events up to it
+    async fn save_position(&mut self) {
+        if self
+            .outstanding_events
+            .values()
+            .any(|results| !results.events.is_empty())
+        {
+            return;
+        }
+        if let Some(position) = self.position.take() {
+            if let Err(e) = self
+                .db
+                .set_feed_position(self.ceramic_url.as_str(), &position)
+                .await
+            {
+                tracing::warn!("Failed to save feed position: {:?}", e);
+                metrics()
+                    .persistence_failures
+                    .with_label_values(&["set_feed_position"])
+                    .inc();
+                self.position = Some(position);
+            }
+        }
+    }
+}
+
+/// Store an event under the client's next sequence, which is only taken if the event is new.
+/// Returns the event if it could not be stored.
+async fn add_event(
+    db: &(dyn Persistence + Send + Sync),
+    client_id: &str,
+    results: &mut PendingResults,
+    event: Event,
+) -> Result<(), Event> {
+    let event = SequencedEvent {
+        sequence: results.sequence + 1,
+        event,
+    };
+    match db
+        .add_event(client_id, &event)
+        .instrument(event_span("persist_event", client_id, &event))
+        .await
+    {
+        Ok(added) => {
+            if added {
+                results.sequence = event.sequence;
+            }
+            Ok(())
         }
+        Err(e) => {
+            tracing::warn!("Failed to persist event: {:?}", e);
+            metrics()
+                .persistence_failures
+                .with_label_values(&["add_event"])
+                .inc();
+            Err(event.event)
+        }
+    }
+}
+
+/// A stage in the trace of a client's event
+fn event_span(stage: &'static str, client_id: &str, event: &SequencedEvent) -> tracing::Span {
+    let span = util::event_span(stage, &event.event.commit_id);
+    span.record("client_id", client_id);
+    span.record("sequence", event.sequence);
+    span
+}
+
+/// Record that events were handed to a client in each event's trace
+fn trace_delivery(client_id: &str, events: &[SequencedEvent]) {
+    for event in events {
+        event_span("deliver_event", client_id, event).in_scope(|| {
+            tracing::trace!("Delivering event {}", event.sequence);
+        });
+    }
+}
+
+/// Errors from the shared feed are reported to every client
+fn client_error(e: &Error) -> Error {
+    match e {
+        Error::FeedGap(feed, position) => Error::FeedGap(feed.clone(), position.clone()),
+        e => Error::custom(e.to_string()),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{BatchCreationParameters, Batcher};
+    use super::{BatchCreationParameters, Batcher, BatcherOptions, ReportedErrors};
+    use crate::auth::Caller;
     use crate::errors::Error;
-    use crate::persistence::Persistence;
+    use crate::persistence::{BatchLimit, Persistence, Registration};
     use ceramic_http_client::{
         ceramic_event::{DidDocument, JwkSigner, Signer, StreamId},
         json_patch, remote, schemars, GetRootSchema, ModelAccountRelation, ModelDefinition,
     };
-    use schema::{CeramicMetadata, Event};
+    use schema::{
+        CeramicMetadata, ContentPredicate, DroppedEvents, Event, EventFilter, EventType, FeedState,
+        RetentionPolicy, SequencedEvent,
+    };
     use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::Arc;
     use std::time::Duration;
     use tokio::sync::Mutex;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
 
     // See https://github.com/ajv-validator/ajv-formats for information on valid formats
     #[derive(Debug, Deserialize, Eq, schemars::JsonSchema, PartialEq, Serialize)]
@@ -246,9 +1162,9 @@ mod tests {
             std::env::var("DID_PRIVATE_KEY").expect("DID_PRIVATE_KEY not set in environment");
         JwkSigner::new(DidDocument::new(&public), &private)
             .await
-            .expect(&format!(
-                "Failed to create did for document {public} with key {private}"
-            ))
+            .unwrap_or_else(|_| {
+                panic!("Failed to create did for document {public} with key {private}")
+            })
     }
 
     pub async fn create_model(cli: &remote::CeramicRemoteHttpClient<JwkSigner>) -> StreamId {
@@ -257,49 +1173,836 @@ mod tests {
     }
 
     struct InMemoryPersistence {
-        events: Arc<Mutex<HashMap<String, Vec<Event>>>>,
+        events: Arc<Mutex<HashMap<String, Vec<SequencedEvent>>>>,
+        positions: Mutex<HashMap<String, String>>,
+        registrations: Mutex<HashMap<String, Registration>>,
+        /// Fail event writes, as if the database were unavailable
+        fail_writes: AtomicBool,
     }
 
     impl InMemoryPersistence {
         pub fn new() -> Self {
             Self {
                 events: Arc::new(Mutex::new(HashMap::new())),
+                positions: Mutex::new(HashMap::new()),
+                registrations: Mutex::new(HashMap::new()),
+                fail_writes: AtomicBool::new(false),
             }
         }
     }
 
     #[async_trait::async_trait]
     impl Persistence for InMemoryPersistence {
-        async fn add_event(&self, client_id: &str, event: &Event) -> Result<(), Error> {
+        async fn add_event(&self, client_id: &str, event: &SequencedEvent) -> Result<bool, Error> {
+            if self.fail_writes.load(Ordering::Relaxed) {
+                return Err(Error::custom("Writes are failing"));
+            }
             let mut events = self.events.lock().await;
-            events
-                .entry(client_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(event.clone());
-            Ok(())
+            let events = events.entry(client_id.to_string()).or_insert_with(Vec::new);
+            if events
+                .iter()
+                .any(|ev| ev.event.commit_id == event.event.commit_id)
+            {
+                return Ok(false);
+            }
+            events.push(event.clone());
+            if let Some(registration) = self.registrations.lock().await.get_mut(client_id) {
+                registration.last_sequence = registration.last_sequence.max(event.sequence);
+            }
+            Ok(true)
         }
-        async fn get_events(&self, client_id: &str) -> Result<Vec<Event>, Error> {
+        async fn get_events(
+            &self,
+            client_id: &str,
+            cursor: i64,
+            limit: BatchLimit,
+        ) -> Result<Vec<SequencedEvent>, Error> {
+            let events = self.events.lock().await;
+            let mut total = 0;
+            Ok(events
+                .get(client_id)
+                .map(|evs| {
+                    evs.iter()
+                        .filter(|ev| ev.sequence > cursor)
+                        .take_while(|ev| {
+                            let under = total < limit.max_bytes.unwrap_or(usize::MAX);
+                            total += serde_json::to_string(&ev.event).unwrap().len();
+                            under
+                        })
+                        .take(limit.max_events.unwrap_or(usize::MAX))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default())
+        }
+        async fn ack_events(&self, client_id: &str, cursor: i64) -> Result<(), Error> {
             let mut events = self.events.lock().await;
             if let Some(evs) = events.get_mut(client_id) {
-                Ok(std::mem::replace(evs, Vec::new()))
-            } else {
-                Ok(Vec::new())
+                evs.retain(|ev| ev.sequence > cursor);
+            }
+            Ok(())
+        }
+        async fn set_feed_position(&self, feed: &str, position: &str) -> Result<(), Error> {
+            self.positions
+                .lock()
+                .await
+                .insert(feed.to_string(), position.to_string());
+            Ok(())
+        }
+        async fn get_feed_position(&self, feed: &str) -> Result<Option<String>, Error> {
+            Ok(self.positions.lock().await.get(feed).cloned())
+        }
+        async fn count_events(&self, client_id: &str) -> Result<i64, Error> {
+            let events = self.events.lock().await;
+            Ok(events.get(client_id).map_or(0, |evs| evs.len() as i64))
+        }
+        async fn add_registration(&self, registration: &Registration) -> Result<(), Error> {
+            self.registrations
+                .lock()
+                .await
+                .entry(registration.client_id.clone())
+                .and_modify(|existing| {
+                    existing.filter = registration.filter.clone();
+                    if existing.owner.is_none() {
+                        existing.owner = registration.owner.clone();
+                    }
+                })
+                .or_insert_with(|| registration.clone());
+            Ok(())
+        }
+        async fn get_registrations(&self) -> Result<Vec<Registration>, Error> {
+            Ok(self.registrations.lock().await.values().cloned().collect())
+        }
+        async fn delete_client(&self, client_id: &str, _archive: bool) -> Result<(), Error> {
+            self.events.lock().await.remove(client_id);
+            self.registrations.lock().await.remove(client_id);
+            Ok(())
+        }
+        /// Only the event count limit is applied in memory
+        async fn apply_retention(
+            &self,
+            client_id: &str,
+            policy: &RetentionPolicy,
+            _now: i64,
+        ) -> Result<DroppedEvents, Error> {
+            let mut dropped = DroppedEvents::default();
+            let mut events = self.events.lock().await;
+            if let (Some(evs), Some(max_events)) = (events.get_mut(client_id), policy.max_events) {
+                let excess = evs.len().saturating_sub(max_events as usize);
+                evs.drain(..excess);
+                dropped.evicted = excess as u64;
             }
+            Ok(dropped)
+        }
+    }
+
+    async fn mock_feed(events: &[Event]) -> MockServer {
+        let server = MockServer::start().await;
+        let body: String = events
+            .iter()
+            .map(|ev| format!("data: {}\n\n", serde_json::to_string(ev).unwrap()))
+            .collect();
+        Mock::given(method("GET"))
+            .and(path("/api/v0/feed/aggregation/documents"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    const OWNER: &str = "did:key:owner";
+
+    fn caller(did: &str, admin: bool) -> Caller {
+        Caller {
+            did: did.to_string(),
+            admin,
+        }
+    }
+
+    fn limit(max_events: usize) -> BatchLimit {
+        BatchLimit {
+            max_events: Some(max_events),
+            ..Default::default()
+        }
+    }
+
+    fn params(client_id: &str) -> BatchCreationParameters {
+        BatchCreationParameters {
+            client_id: client_id.to_string(),
+            filter: EventFilter::default(),
+            retention: RetentionPolicy::default(),
+        }
+    }
+
+    /// Start a batcher on the feed served by `server` and register `params` for [`OWNER`]
+    async fn start_with(
+        server: &MockServer,
+        db: Arc<InMemoryPersistence>,
+        options: BatcherOptions,
+        params: BatchCreationParameters,
+    ) -> Batcher {
+        let url = url::Url::parse(&server.uri()).unwrap();
+        let batcher = Batcher::new_with_options(db, url, options);
+        batcher
+            .create_batcher(params, &caller(OWNER, false))
+            .await
+            .unwrap();
+        batcher
+    }
+
+    /// Start a batcher on the feed served by `server` with a "test" client owned by [`OWNER`]
+    async fn start(
+        server: &MockServer,
+        options: BatcherOptions,
+    ) -> (Batcher, Arc<InMemoryPersistence>) {
+        let db = Arc::new(InMemoryPersistence::new());
+        let batcher = start_with(server, db.clone(), options, params("test")).await;
+        (batcher, db)
+    }
+
+    fn event(commit_id: &str) -> Event {
+        Event {
+            commit_id: commit_id.to_string(),
+            event_type: EventType::Data,
+            content: "{}".to_string(),
+            metadata: serde_json::Value::Null,
         }
     }
 
+    #[tokio::test]
+    async fn should_wait_for_events() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("commit")]).await;
+        let (batcher, _) = start(&server, BatcherOptions::default()).await;
+        let batch = batcher
+            .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(batch.events[0].event.commit_id, "commit");
+    }
+
+    #[tokio::test]
+    async fn should_cap_requested_wait() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[]).await;
+        let (batcher, _) = start(
+            &server,
+            BatcherOptions {
+                max_wait: Duration::from_millis(100),
+                ..Default::default()
+            },
+        )
+        .await;
+        let batch = tokio::time::timeout(
+            Duration::from_secs(5),
+            batcher.get_batch("test", BatchLimit::default(), Some(Duration::MAX)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(batch.events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_redeliver_unacknowledged_events() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("commit")]).await;
+        let (batcher, _) = start(
+            &server,
+            BatcherOptions {
+                ack_timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+        )
+        .await;
+        let batch = batcher
+            .get_batch("test", limit(1), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        let cursor = batch.cursor.unwrap();
+        assert_eq!(batch.events[0].sequence, cursor);
+        assert!(batcher
+            .get_batch("test", BatchLimit::default(), None)
+            .await
+            .unwrap()
+            .events
+            .is_empty());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let redelivered = batcher.get_batch("test", limit(1), None).await.unwrap();
+        assert_eq!(redelivered.cursor, Some(cursor));
+        assert_eq!(redelivered.events[0].event.commit_id, "commit");
+
+        batcher.ack("test", cursor).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let batch = batcher.get_batch("test", limit(1), None).await.unwrap();
+        assert!(batch.cursor.is_none_or(|c| c > cursor));
+    }
+
+    #[tokio::test]
+    async fn should_reject_acks_past_delivered_events() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("first"), event("second")]).await;
+        let (batcher, _) = start(&server, BatcherOptions::default()).await;
+        let read = batcher
+            .read_batch(
+                "test",
+                0,
+                &ReportedErrors::default(),
+                limit(1),
+                Some(Duration::from_secs(5)),
+            )
+            .await
+            .unwrap();
+        let cursor = read.cursor.unwrap();
+        assert!(matches!(
+            batcher.ack("test", cursor + 1).await,
+            Err(Error::BadRequest(_))
+        ));
+        // streamed events may be acknowledged without being polled
+        batcher.ack("test", cursor).await.unwrap();
+        let batch = batcher
+            .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert!(batch.events.iter().all(|ev| ev.sequence > cursor));
+    }
+
+    #[tokio::test]
+    async fn should_resume_from_saved_position() {
+        let _ = env_logger::try_init();
+
+        let server = MockServer::start().await;
+        let body = format!(
+            "id: 5\ndata: {}\n\n",
+            serde_json::to_string(&event("commit")).unwrap()
+        );
+        Mock::given(method("GET"))
+            .and(path("/api/v0/feed/aggregation/documents"))
+            .and(header("Last-Event-ID", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+        let url = url::Url::parse(&server.uri()).unwrap();
+        let db = Arc::new(InMemoryPersistence::new());
+        db.set_feed_position(url.as_str(), "2").await.unwrap();
+        let batcher = start_with(
+            &server,
+            db.clone(),
+            BatcherOptions::default(),
+            params("test"),
+        )
+        .await;
+        let batch = batcher
+            .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(batch.events[0].event.commit_id, "commit");
+        assert_eq!(
+            db.get_feed_position(url.as_str()).await.unwrap(),
+            Some("5".to_string())
+        );
+        // reading does not take the error from the polling consumer
+        batcher
+            .read_batch(
+                "test",
+                batch.cursor.unwrap(),
+                &ReportedErrors::default(),
+                BatchLimit::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        // the feed skipped from 2 to 5, which is reported once the events are delivered
+        assert!(matches!(
+            batcher
+                .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+                .await,
+            Err(Error::FeedGap(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_report_status_and_delete_batcher() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("commit")]).await;
+        let (batcher, db) = start(&server, BatcherOptions::default()).await;
+        let batch = batcher
+            .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert!(!batch.events.is_empty());
+
+        let status = batcher.status("test").await.unwrap();
+        assert_eq!(status.persisted_events, batch.events.len() as i64);
+        assert_eq!(status.buffered_events, 0);
+        assert!(status.last_event_at.is_some());
+        let statuses = batcher.list().await.unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].client_id, "test");
+
+        batcher.delete_batcher("test", false).await.unwrap();
+        assert!(batcher.list().await.unwrap().is_empty());
+        assert!(db
+            .get_events("test", 0, BatchLimit::default())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            batcher.get_batch("test", BatchLimit::default(), None).await,
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            batcher.delete_batcher("test", false).await,
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            batcher.status("test").await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_filter_events_before_persisting() {
+        let _ = env_logger::try_init();
+
+        let mut wanted = event("wanted");
+        wanted.metadata = serde_json::json!({"model": "attestation", "controllers": ["did:key:a"]});
+        wanted.content = r#"{"data":{"context":"depin"}}"#.to_string();
+        let mut other_model = wanted.clone();
+        other_model.commit_id = "other_model".to_string();
+        other_model.metadata["model"] = "other".into();
+        let mut other_content = wanted.clone();
+        other_content.commit_id = "other_content".to_string();
+        other_content.content = r#"{"data":{"context":"other"}}"#.to_string();
+        let server = mock_feed(&[other_model, other_content, wanted]).await;
+        let db = Arc::new(InMemoryPersistence::new());
+        let filter = EventFilter {
+            models: vec!["attestation".to_string()],
+            event_types: vec![EventType::Data],
+            content: vec![ContentPredicate {
+                pointer: "/data/context".to_string(),
+                equals: Some("depin".into()),
+            }],
+            ..Default::default()
+        };
+        let batcher = start_with(
+            &server,
+            db.clone(),
+            BatcherOptions::default(),
+            BatchCreationParameters {
+                filter,
+                ..params("test")
+            },
+        )
+        .await;
+        let batch = batcher
+            .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert!(batch.events.iter().all(|ev| ev.event.commit_id == "wanted"));
+        let stored = db
+            .get_events("test", 0, BatchLimit::default())
+            .await
+            .unwrap();
+        assert!(stored.iter().all(|ev| ev.event.commit_id == "wanted"));
+    }
+
+    #[tokio::test]
+    async fn should_restore_registrations() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("commit")]).await;
+        let db = Arc::new(InMemoryPersistence::new());
+        let filter = EventFilter {
+            event_types: vec![EventType::Data],
+            ..Default::default()
+        };
+        let batcher = start_with(
+            &server,
+            db.clone(),
+            BatcherOptions::default(),
+            BatchCreationParameters {
+                filter,
+                ..params("test")
+            },
+        )
+        .await;
+        let created_at = batcher.status("test").await.unwrap().created_at;
+        drop(batcher);
+
+        let url = url::Url::parse(&server.uri()).unwrap();
+        let batcher = Batcher::new_with_options(db, url, BatcherOptions::default());
+        let status = batcher.status("test").await.unwrap();
+        assert_eq!(status.created_at, created_at);
+        assert_eq!(status.owner.as_deref(), Some(OWNER));
+        let batch = batcher
+            .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(batch.events[0].event.commit_id, "commit");
+    }
+
+    #[tokio::test]
+    async fn should_bind_batcher_to_owner() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("commit")]).await;
+        let db = Arc::new(InMemoryPersistence::new());
+        // registered before batchers were bound to an owner
+        db.add_registration(&Registration {
+            client_id: "unowned".to_string(),
+            filter: EventFilter::default(),
+            retention: RetentionPolicy::default(),
+            created_at: 1,
+            last_sequence: 0,
+            owner: None,
+        })
+        .await
+        .unwrap();
+        let batcher = start_with(&server, db, BatcherOptions::default(), params("test")).await;
+        batcher
+            .create_batcher(params("test"), &caller(OWNER, false))
+            .await
+            .unwrap();
+        assert!(matches!(
+            batcher
+                .create_batcher(params("test"), &caller("did:key:other", false))
+                .await,
+            Err(Error::Forbidden(_))
+        ));
+        assert_eq!(
+            batcher.status("test").await.unwrap().owner.as_deref(),
+            Some(OWNER)
+        );
+        assert_eq!(batcher.owner("test").await.unwrap().as_deref(), Some(OWNER));
+        assert!(matches!(
+            batcher.owner("missing").await,
+            Err(Error::NotFound(_))
+        ));
+
+        assert_eq!(batcher.status("unowned").await.unwrap().owner, None);
+        assert!(matches!(
+            batcher
+                .create_batcher(params("unowned"), &caller("did:key:other", false))
+                .await,
+            Err(Error::Forbidden(_))
+        ));
+        assert_eq!(batcher.status("unowned").await.unwrap().owner, None);
+        batcher
+            .create_batcher(params("unowned"), &caller("did:key:admin", true))
+            .await
+            .unwrap();
+        assert_eq!(
+            batcher.status("unowned").await.unwrap().owner.as_deref(),
+            Some("did:key:admin")
+        );
+    }
+
+    #[tokio::test]
+    async fn should_share_feed_between_clients() {
+        let _ = env_logger::try_init();
+
+        let server = MockServer::start().await;
+        let body = format!(
+            "data: {}\n\n",
+            serde_json::to_string(&event("commit")).unwrap()
+        );
+        // delay the feed so both clients are registered before it delivers
+        Mock::given(method("GET"))
+            .and(path("/api/v0/feed/aggregation/documents"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(body, "text/event-stream")
+                    .set_delay(Duration::from_millis(200)),
+            )
+            .mount(&server)
+            .await;
+        let db = Arc::new(InMemoryPersistence::new());
+        let batcher = start_with(&server, db, BatcherOptions::default(), params("first")).await;
+        batcher
+            .create_batcher(params("second"), &caller(OWNER, false))
+            .await
+            .unwrap();
+        for client_id in ["first", "second"] {
+            let batch = batcher
+                .get_batch(
+                    client_id,
+                    BatchLimit::default(),
+                    Some(Duration::from_secs(5)),
+                )
+                .await
+                .unwrap();
+            assert_eq!(batch.events[0].event.commit_id, "commit");
+            assert_eq!(batch.events[0].sequence, 1);
+        }
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_pause_feed_when_buffer_is_full() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("first"), event("second"), event("third")]).await;
+        let db = Arc::new(InMemoryPersistence::new());
+        db.fail_writes.store(true, Ordering::Relaxed);
+        let options = BatcherOptions {
+            max_buffered_events: 1,
+            ..Default::default()
+        };
+        let batcher = start_with(&server, db.clone(), options, params("test")).await;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            let status = batcher.status("test").await.unwrap();
+            if status.last_error.is_some() {
+                assert_eq!(status.buffered_events, 1);
+                assert_eq!(status.feed_state, FeedState::Reconnecting);
+                break;
+            }
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        db.fail_writes.store(false, Ordering::Relaxed);
+        let mut events = vec![];
+        while events.len() < 3 {
+            let batch = batcher
+                .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+                .await
+                .unwrap();
+            assert!(!batch.events.is_empty());
+            events.extend(batch.events);
+        }
+        let commit_ids: Vec<_> = events
+            .iter()
+            .map(|ev| ev.event.commit_id.as_str())
+            .collect();
+        assert_eq!(commit_ids, vec!["first", "second", "third"]);
+        assert!(events.windows(2).all(|w| w[0].sequence < w[1].sequence));
+    }
+
+    #[tokio::test]
+    async fn should_store_buffered_events_on_shutdown() {
+        let _ = env_logger::try_init();
+
+        let server = MockServer::start().await;
+        let body: String = ["first", "second", "third"]
+            .iter()
+            .enumerate()
+            .map(|(i, commit_id)| {
+                format!(
+                    "id: {}\ndata: {}\n\n",
+                    i + 1,
+                    serde_json::to_string(&event(commit_id)).unwrap()
+                )
+            })
+            .collect();
+        Mock::given(method("GET"))
+            .and(path("/api/v0/feed/aggregation/documents"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+        let url = url::Url::parse(&server.uri()).unwrap();
+        let db = Arc::new(InMemoryPersistence::new());
+        db.fail_writes.store(true, Ordering::Relaxed);
+        let batcher = start_with(
+            &server,
+            db.clone(),
+            BatcherOptions::default(),
+            params("test"),
+        )
+        .await;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while batcher.status("test").await.unwrap().buffered_events < 3 {
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        db.fail_writes.store(false, Ordering::Relaxed);
+        batcher.shutdown().await.unwrap();
+        let events = db.events.lock().await.get("test").cloned().unwrap();
+        let commit_ids: Vec<_> = events
+            .iter()
+            .map(|ev| ev.event.commit_id.as_str())
+            .collect();
+        assert_eq!(commit_ids, vec!["first", "second", "third"]);
+        assert_eq!(
+            db.get_feed_position(url.as_str()).await.unwrap(),
+            Some("3".to_string())
+        );
+        assert!(batcher.status("test").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_apply_retention_policy() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("first"), event("second"), event("third")]).await;
+        let options = BatcherOptions {
+            retention_interval: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let retention = RetentionPolicy {
+            max_events: Some(1),
+            ..Default::default()
+        };
+        let batcher = start_with(
+            &server,
+            Arc::new(InMemoryPersistence::new()),
+            options,
+            BatchCreationParameters {
+                retention,
+                ..params("test")
+            },
+        )
+        .await;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            let status = batcher.status("test").await.unwrap();
+            if status.dropped_events.evicted == 2 {
+                assert_eq!(status.persisted_events, 1);
+                break;
+            }
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let batch = batcher
+            .get_batch("test", BatchLimit::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(batch.events[0].event.commit_id, "third");
+    }
+
+    #[tokio::test]
+    async fn should_continue_sequences_after_restart() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("first"), event("second")]).await;
+        let (batcher, db) = start(&server, BatcherOptions::default()).await;
+        let mut events = vec![];
+        while events.len() < 2 {
+            let batch = batcher
+                .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+                .await
+                .unwrap();
+            events.extend(batch.events);
+        }
+        let sequences: Vec<_> = events.iter().map(|ev| ev.sequence).collect();
+        assert_eq!(sequences, vec![1, 2]);
+        batcher.ack("test", 2).await.unwrap();
+        drop(batcher);
+
+        let server = mock_feed(&[event("third")]).await;
+        let batcher = Batcher::new_with_options(
+            db,
+            url::Url::parse(&server.uri()).unwrap(),
+            BatcherOptions::default(),
+        );
+        let batch = batcher
+            .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(batch.events[0].event.commit_id, "third");
+        assert_eq!(batch.events[0].sequence, 3);
+    }
+
+    #[tokio::test]
+    async fn should_not_sequence_redelivered_events() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("first"), event("first"), event("second")]).await;
+        let (batcher, db) = start(&server, BatcherOptions::default()).await;
+        let mut events = vec![];
+        while events.len() < 2 {
+            let batch = batcher
+                .get_batch("test", BatchLimit::default(), Some(Duration::from_secs(5)))
+                .await
+                .unwrap();
+            events.extend(batch.events);
+        }
+        let sequences: Vec<_> = events.iter().map(|ev| ev.sequence).collect();
+        assert_eq!(sequences, vec![1, 2]);
+        let registrations = db.get_registrations().await.unwrap();
+        assert_eq!(registrations[0].last_sequence, 2);
+    }
+
+    #[tokio::test]
+    async fn should_read_without_leasing_events() {
+        let _ = env_logger::try_init();
+
+        let server = mock_feed(&[event("commit")]).await;
+        let (batcher, _) = start(&server, BatcherOptions::default()).await;
+        let read = batcher
+            .read_batch(
+                "test",
+                0,
+                &ReportedErrors::default(),
+                limit(1),
+                Some(Duration::from_secs(5)),
+            )
+            .await
+            .unwrap();
+        let cursor = read.cursor.unwrap();
+        assert_eq!(read.events[0].event.commit_id, "commit");
+        assert!(batcher
+            .read_batch("test", cursor, &ReportedErrors::default(), limit(1), None)
+            .await
+            .unwrap()
+            .events
+            .iter()
+            .all(|ev| ev.sequence > cursor));
+        // the read did not lease the event, so polling still receives it
+        let batch = batcher.get_batch("test", limit(1), None).await.unwrap();
+        assert_eq!(batch.cursor, Some(cursor));
+    }
+
+    #[tokio::test]
+    async fn should_time_out_waiting_for_events() {
+        let _ = env_logger::try_init();
+
+        let server = MockServer::start().await;
+        let (batcher, _) = start(&server, BatcherOptions::default()).await;
+        let start = std::time::Instant::now();
+        let batch = batcher
+            .get_batch(
+                "test",
+                BatchLimit::default(),
+                Some(Duration::from_millis(200)),
+            )
+            .await
+            .unwrap();
+        assert!(batch.events.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(matches!(
+            batcher
+                .get_batch("missing", BatchLimit::default(), None)
+                .await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
     #[tokio::test]
     async fn should_receive_create_and_update_events() {
         let _ = env_logger::try_init();
 
         let ceramic_url = ceramic_url();
         let db = Arc::new(InMemoryPersistence::new());
-        let batcher = Batcher::new_with_url(db, ceramic_url.clone());
+        let batcher = Batcher::new_with_options(db, ceramic_url.clone(), BatcherOptions::default());
         let client_id = "test";
         batcher
-            .create_batcher(BatchCreationParameters {
-                client_id: client_id.to_string(),
-            })
+            .create_batcher(
+                BatchCreationParameters {
+                    client_id: client_id.to_string(),
+                    filter: EventFilter::default(),
+                    retention: RetentionPolicy::default(),
+                },
+                &caller(OWNER, false),
+            )
             .await
             .unwrap();
 
@@ -357,7 +2060,11 @@ mod tests {
         assert_eq!(get_resp.blue, 8);
         assert_eq!(get_resp, post_resp);
 
-        let batch = batcher.get_batch(client_id, None).await.unwrap();
+        let batch = batcher
+            .get_batch(client_id, BatchLimit::default(), None)
+            .await
+            .unwrap();
+        let batch: Vec<_> = batch.events.into_iter().map(|ev| ev.event).collect();
         assert!(batch.len() >= 4);
         //model create will have the "parent" model is as the model in metadata
         //we will see the create and the anchor for the model, and then see mids, which have
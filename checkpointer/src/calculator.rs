@@ -1,69 +1,112 @@
 use crate::ceramic::Ceramic;
+use crate::config::Settings;
 use crate::errors::Error;
-use crate::event_source::EventSource;
+use crate::event_source::{EventSource, FeedEvent};
+use crate::metrics::metrics;
 use ceramic_http_client::ceramic_event::{DidDocument, JwkSigner};
 use ceramic_http_client::remote::CeramicRemoteHttpClient;
 use schema::{Event, EventType};
-use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::Instrument;
 use url::Url;
 
 #[derive(Clone, Debug)]
 pub struct CalculatorParameters {
     pub ceramic_url: Url,
     pub signer: JwkSigner,
+    pub calculator: calculator::CalculatorParameters,
+    pub streak_refresh: Duration,
 }
 
 impl CalculatorParameters {
-    pub async fn new() -> Result<Self, Error> {
-        let did = std::env::var("DID_DOCUMENT").unwrap_or_else(|_| {
-            "did:key:z6Mkk3rtfoKDMMG4zyarNGwCQs44GSQ49pcYKQspHJPXSnVw".to_string()
-        });
-        let pk =
-            std::env::var("DID_PRIVATE_KEY").map_err(|_| Error::custom("Invalid PRIVATE_KEY"))?;
-        let url =
-            std::env::var("CERAMIC_URL").unwrap_or_else(|_| "http://localhost:70007".to_string());
-        tracing::info!("Creating calculator with DID {} using ceramic {}", did, url);
-        let did = DidDocument::new(&did);
-        let signer = JwkSigner::new(did.clone(), &pk).await?;
+    pub async fn new(settings: &Settings) -> Result<Self, Error> {
+        let pk = settings
+            .did_private_key
+            .as_ref()
+            .ok_or_else(|| Error::custom("Invalid PRIVATE_KEY"))?;
+        tracing::info!(
+            "Creating calculator with DID {} using ceramic {}",
+            settings.did_document,
+            settings.ceramic_url
+        );
+        let did = DidDocument::new(&settings.did_document);
+        let signer = JwkSigner::new(did.clone(), pk).await?;
         Ok(Self {
-            ceramic_url: Url::from_str(&url)?,
+            ceramic_url: settings.ceramic_url.clone(),
             signer,
+            calculator: calculator::CalculatorParameters::from_settings(&settings.calculator)?,
+            streak_refresh: Duration::from_secs(settings.streak_refresh_seconds),
         })
     }
 }
 
 pub struct Calculator {
     url: Url,
+    streak_refresh: Duration,
     inner: calculator::Calculator,
 }
 
 impl Calculator {
     pub fn new(params: CalculatorParameters) -> Result<Calculator, Error> {
         let url = params.ceramic_url.clone();
-        let cli = CeramicRemoteHttpClient::new(params.signer, params.ceramic_url);
-        let cli = Box::new(Ceramic::new(cli));
-        let calc = calculator::Calculator::new(calculator::CalculatorParameters::new()?, cli);
-        Ok(Self { url, inner: calc })
+        let streak_refresh = params.streak_refresh;
+        let cli = CeramicRemoteHttpClient::new(params.signer, params.ceramic_url.clone());
+        let cli = Box::new(Ceramic::new(params.ceramic_url, cli));
+        let calc = calculator::Calculator::new_with_metrics(
+            params.calculator,
+            cli,
+            metrics().calculator.clone(),
+        );
+        Ok(Self {
+            url,
+            streak_refresh,
+            inner: calc,
+        })
     }
 
     pub async fn process_event(&mut self, event: Event) -> Result<(), Error> {
-        Ok(self.inner.process_event(event).await?)
+        let span = util::event_span("calculate", &event.commit_id);
+        Ok(self.inner.process_event(event).instrument(span).await?)
+    }
+
+    /// Recompute every holder's streaks, ending those without attestations in the current period
+    pub async fn refresh_streaks(&mut self) -> Result<(), Error> {
+        Ok(self.inner.refresh_streaks().await?)
     }
 
-    pub fn run(self) {
-        tokio::spawn(run(self));
+    /// Process the feed until `shutdown` is signalled, finishing the event being processed
+    pub fn run(self, shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        tokio::spawn(run(self, shutdown))
     }
 }
 
-async fn run(mut calculator: Calculator) {
-    let es = EventSource::new("ceramic-calculator", &calculator.url);
+async fn run(mut calculator: Calculator, mut shutdown: watch::Receiver<bool>) {
+    let es = EventSource::new("ceramic-calculator", &calculator.url, None);
     let mut running = es.run();
 
     tracing::info!("Starting calculator against {}", calculator.url);
 
-    while let Some(event) = running.rx.recv().await {
+    let mut streak_refresh = tokio::time::interval(calculator.streak_refresh);
+    streak_refresh.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    while !*shutdown.borrow() {
+        let event = tokio::select! {
+            event = running.rx.recv() => event,
+            _ = streak_refresh.tick() => {
+                if let Err(e) = calculator.refresh_streaks().await {
+                    tracing::error!("Error refreshing streaks: {}", e);
+                }
+                continue;
+            }
+            _ = shutdown.changed() => break,
+        };
+        let Some(event) = event else {
+            break;
+        };
         match event {
-            Ok(event) => {
+            Ok(FeedEvent { event, .. }) => {
                 if event.event_type == EventType::Data || event.event_type == EventType::Init {
                     if let Err(e) = calculator.process_event(event).await {
                         tracing::error!("Error processing event: {}", e);
@@ -76,5 +119,9 @@ async fn run(mut calculator: Calculator) {
         }
     }
 
+    let _ = running.shutdown.send(true);
+    if let Err(e) = running.join.await {
+        tracing::warn!("Event source failed: {:?}", e);
+    }
     tracing::info!("Calculator stopped");
 }
@@ -1,16 +1,24 @@
+use calculator::{CollectionPage, COLLECTION_ENDPOINT};
 use ceramic_http_client::api::{self, Pagination};
 use ceramic_http_client::ceramic_event::{JwkSigner, StreamId};
 use ceramic_http_client::remote::CeramicRemoteHttpClient;
 use ceramic_http_client::FilterQuery;
 use models::PointMaterialization;
+use url::Url;
 
 pub struct Ceramic {
+    url: Url,
     inner: CeramicRemoteHttpClient<JwkSigner>,
+    http: reqwest::Client,
 }
 
 impl Ceramic {
-    pub fn new(inner: CeramicRemoteHttpClient<JwkSigner>) -> Self {
-        Self { inner }
+    pub fn new(url: Url, inner: CeramicRemoteHttpClient<JwkSigner>) -> Self {
+        Self {
+            url,
+            inner,
+            http: reqwest::Client::new(),
+        }
     }
 }
 
@@ -26,6 +34,7 @@ impl calculator::Ceramic for Ceramic {
             .await
     }
 
+    #[tracing::instrument(skip(self, data))]
     async fn create(
         &self,
         model_id: &StreamId,
@@ -34,6 +43,7 @@ impl calculator::Ceramic for Ceramic {
         self.inner.create_list_instance(model_id, data).await
     }
 
+    #[tracing::instrument(skip(self, data))]
     async fn replace(
         &self,
         model_id: &StreamId,
@@ -46,4 +56,21 @@ impl calculator::Ceramic for Ceramic {
             .await?
             .stream_id)
     }
+
+    async fn collection(
+        &self,
+        request: serde_json::Value,
+    ) -> Result<CollectionPage, anyhow::Error> {
+        let body = self
+            .http
+            .post(self.url.join(COLLECTION_ENDPOINT)?)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&request)?)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
 }
@@ -0,0 +1,474 @@
+use crate::batcher::{
+    BatcherOptions, DEFAULT_ACK_TIMEOUT, DEFAULT_MAX_BATCH_EVENTS, DEFAULT_MAX_BUFFERED_EVENTS,
+    DEFAULT_MAX_WAIT, DEFAULT_RETENTION_INTERVAL, MAX_WAIT_LIMIT,
+};
+use crate::errors::Error;
+use calculator::CalculatorSettings;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+const REDACTED: &str = "<redacted>";
+
+/// Checkpointer settings. Each value is taken from the first of the command line flags, the
+/// environment, the config file and the defaults that sets it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub bind_address: SocketAddr,
+    pub ceramic_url: Url,
+    pub database_url: String,
+    /// DID the checkpointer signs materializations with, matching `did_private_key`
+    pub did_document: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub did_private_key: Option<String>,
+    pub token_ttl_seconds: i64,
+    /// How long shutdown may take to store buffered events and finish calculator writes
+    pub shutdown_timeout_seconds: u64,
+    /// How often every holder's streaks are recomputed, ending those that lapsed
+    pub streak_refresh_seconds: u64,
+    pub auth: AuthSettings,
+    pub batcher: BatcherSettings,
+    pub calculator: CalculatorSettings,
+    pub tracing: TracingSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            ceramic_url: Url::parse("http://localhost:7007").unwrap(),
+            database_url: String::new(),
+            did_document: "did:key:z6Mkk3rtfoKDMMG4zyarNGwCQs44GSQ49pcYKQspHJPXSnVw".to_string(),
+            did_private_key: None,
+            token_ttl_seconds: 300,
+            shutdown_timeout_seconds: 30,
+            streak_refresh_seconds: 3600,
+            auth: AuthSettings::default(),
+            batcher: BatcherSettings::default(),
+            calculator: CalculatorSettings::default(),
+            tracing: TracingSettings::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// DIDs allowed to use admin routes and every client's batcher
+    pub admin_dids: Vec<String>,
+    /// Longest lifetime accepted for a request token, limiting how long a leaked token is useful
+    pub max_token_lifetime_seconds: i64,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            admin_dids: vec![],
+            max_token_lifetime_seconds: 300,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatcherSettings {
+    pub ack_timeout_seconds: u64,
+    pub max_batch_events: usize,
+    pub max_buffered_events: usize,
+    pub retention_interval_seconds: u64,
+    /// Longest a batch request may wait for events, whatever `wait_ms` it asks for
+    pub max_wait_ms: u64,
+}
+
+impl Default for BatcherSettings {
+    fn default() -> Self {
+        Self {
+            ack_timeout_seconds: DEFAULT_ACK_TIMEOUT.as_secs(),
+            max_batch_events: DEFAULT_MAX_BATCH_EVENTS,
+            max_buffered_events: DEFAULT_MAX_BUFFERED_EVENTS,
+            retention_interval_seconds: DEFAULT_RETENTION_INTERVAL.as_secs(),
+            max_wait_ms: DEFAULT_MAX_WAIT.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingSettings {
+    /// OTLP/HTTP collector to export spans to, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub otlp_endpoint: Option<Url>,
+    pub service_name: String,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "checkpointer".to_string(),
+        }
+    }
+}
+
+impl From<&TracingSettings> for util::TracingOptions {
+    fn from(settings: &TracingSettings) -> Self {
+        Self {
+            otlp_endpoint: settings.otlp_endpoint.as_ref().map(|u| u.to_string()),
+            service_name: settings.service_name.clone(),
+        }
+    }
+}
+
+impl From<&BatcherSettings> for BatcherOptions {
+    fn from(settings: &BatcherSettings) -> Self {
+        Self {
+            ack_timeout: Duration::from_secs(settings.ack_timeout_seconds),
+            max_batch_events: settings.max_batch_events,
+            max_buffered_events: settings.max_buffered_events,
+            retention_interval: Duration::from_secs(settings.retention_interval_seconds),
+            max_wait: Duration::from_millis(settings.max_wait_ms),
+        }
+    }
+}
+
+/// Settings given as command line flags or environment variables, which take precedence over the
+/// config file
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Overrides {
+    #[arg(long, env = "BIND_ADDRESS", global = true)]
+    pub bind_address: Option<SocketAddr>,
+    #[arg(long, env = "CERAMIC_URL", global = true)]
+    pub ceramic_url: Option<Url>,
+    #[arg(long, env = "DATABASE_URL", global = true)]
+    pub database_url: Option<String>,
+    #[arg(long, env = "DID_DOCUMENT", global = true)]
+    pub did_document: Option<String>,
+    #[arg(long, env = "DID_PRIVATE_KEY", hide_env_values = true, global = true)]
+    pub did_private_key: Option<String>,
+    #[arg(long, env = "TOKEN_TTL_SECONDS", global = true)]
+    pub token_ttl_seconds: Option<i64>,
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECONDS", global = true)]
+    pub shutdown_timeout_seconds: Option<u64>,
+    #[arg(long, env = "STREAK_REFRESH_SECONDS", global = true)]
+    pub streak_refresh_seconds: Option<u64>,
+    #[arg(long, env = "ADMIN_DIDS", value_delimiter = ',', global = true)]
+    pub admin_dids: Option<Vec<String>>,
+    #[arg(long, env = "MAX_TOKEN_LIFETIME_SECONDS", global = true)]
+    pub max_token_lifetime_seconds: Option<i64>,
+    #[arg(long, env = "ACK_TIMEOUT_SECONDS", global = true)]
+    pub ack_timeout_seconds: Option<u64>,
+    #[arg(long, env = "MAX_BATCH_EVENTS", global = true)]
+    pub max_batch_events: Option<usize>,
+    #[arg(long, env = "MAX_BUFFERED_EVENTS", global = true)]
+    pub max_buffered_events: Option<usize>,
+    #[arg(long, env = "RETENTION_INTERVAL_SECONDS", global = true)]
+    pub retention_interval_seconds: Option<u64>,
+    #[arg(long, env = "MAX_WAIT_MS", global = true)]
+    pub max_wait_ms: Option<u64>,
+    #[arg(long, env = "ATTESTATION_MODELS", global = true)]
+    pub attestation_models: Option<String>,
+    #[arg(long, env = "ATTESTATION_MODEL_ID", global = true)]
+    pub attestation_model_id: Option<String>,
+    #[arg(long, env = "MATERIALIZATION_MODEL_ID", global = true)]
+    pub materialization_model_id: Option<String>,
+    #[arg(long, env = "ATTESTATION_ISSUER", global = true)]
+    pub attestation_issuer: Option<String>,
+    #[arg(long, env = "REFERRAL_DEPTH", global = true)]
+    pub referral_depth: Option<usize>,
+    #[arg(long, env = "REFERRAL_SHARE_PERCENT", global = true)]
+    pub referral_share_percent: Option<i64>,
+    #[arg(long, env = "STREAKS", global = true)]
+    pub streaks: Option<String>,
+    #[arg(long, env = "COMPLETION_RULES", global = true)]
+    pub completion_rules: Option<String>,
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT", global = true)]
+    pub otlp_endpoint: Option<Url>,
+    #[arg(long, env = "OTEL_SERVICE_NAME", global = true)]
+    pub service_name: Option<String>,
+}
+
+impl Overrides {
+    fn apply(self, settings: &mut Settings) {
+        fn set<T>(value: Option<T>, field: &mut T) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        set(self.bind_address, &mut settings.bind_address);
+        set(self.ceramic_url, &mut settings.ceramic_url);
+        set(self.database_url, &mut settings.database_url);
+        set(self.did_document, &mut settings.did_document);
+        if self.did_private_key.is_some() {
+            settings.did_private_key = self.did_private_key;
+        }
+        set(self.token_ttl_seconds, &mut settings.token_ttl_seconds);
+        set(
+            self.shutdown_timeout_seconds,
+            &mut settings.shutdown_timeout_seconds,
+        );
+        set(
+            self.streak_refresh_seconds,
+            &mut settings.streak_refresh_seconds,
+        );
+
+        let auth = &mut settings.auth;
+        set(self.admin_dids, &mut auth.admin_dids);
+        set(
+            self.max_token_lifetime_seconds,
+            &mut auth.max_token_lifetime_seconds,
+        );
+
+        let batcher = &mut settings.batcher;
+        set(self.ack_timeout_seconds, &mut batcher.ack_timeout_seconds);
+        set(self.max_batch_events, &mut batcher.max_batch_events);
+        set(self.max_buffered_events, &mut batcher.max_buffered_events);
+        set(
+            self.retention_interval_seconds,
+            &mut batcher.retention_interval_seconds,
+        );
+        set(self.max_wait_ms, &mut batcher.max_wait_ms);
+
+        let calculator = &mut settings.calculator;
+        if self.attestation_models.is_some() {
+            calculator.attestation_models = self.attestation_models;
+        }
+        set(
+            self.attestation_model_id,
+            &mut calculator.attestation_model_id,
+        );
+        set(
+            self.materialization_model_id,
+            &mut calculator.materialization_model_id,
+        );
+        set(self.attestation_issuer, &mut calculator.attestation_issuer);
+        set(self.referral_depth, &mut calculator.referral_depth);
+        set(
+            self.referral_share_percent,
+            &mut calculator.referral_share_percent,
+        );
+        set(self.streaks, &mut calculator.streaks);
+        set(self.completion_rules, &mut calculator.completion_rules);
+
+        if self.otlp_endpoint.is_some() {
+            settings.tracing.otlp_endpoint = self.otlp_endpoint;
+        }
+        set(self.service_name, &mut settings.tracing.service_name);
+    }
+}
+
+impl Settings {
+    /// Read the config file if one is given and apply the overrides, without validating the result
+    pub fn load(path: Option<&Path>, overrides: Overrides) -> Result<Self, Error> {
+        let mut settings = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path).map_err(|e| {
+                    Error::Config(format!("Failed to read {}: {}", path.display(), e))
+                })?;
+                toml::from_str(&contents)
+                    .map_err(|e| Error::Config(format!("Invalid {}: {}", path.display(), e)))?
+            }
+            None => Self::default(),
+        };
+        overrides.apply(&mut settings);
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.database_url.is_empty() {
+            return Err(Error::Config("database_url must be set".to_string()));
+        }
+        match self.did_private_key.as_ref() {
+            None => return Err(Error::Config("did_private_key must be set".to_string())),
+            Some(key) if hex::decode(key).is_err() => {
+                return Err(Error::Config("did_private_key must be hex".to_string()))
+            }
+            Some(_) => {}
+        }
+        if self.token_ttl_seconds <= 0 {
+            return Err(Error::Config(
+                "token_ttl_seconds must be positive".to_string(),
+            ));
+        }
+        if self.shutdown_timeout_seconds == 0 {
+            return Err(Error::Config(
+                "shutdown_timeout_seconds must be positive".to_string(),
+            ));
+        }
+        if self.streak_refresh_seconds == 0 {
+            return Err(Error::Config(
+                "streak_refresh_seconds must be positive".to_string(),
+            ));
+        }
+        if self.auth.max_token_lifetime_seconds <= 0 {
+            return Err(Error::Config(
+                "auth max_token_lifetime_seconds must be positive".to_string(),
+            ));
+        }
+        if let Some(did) = self.auth.admin_dids.iter().find(|d| !d.starts_with("did:")) {
+            return Err(Error::Config(format!("admin DID {} is not a DID", did)));
+        }
+        if self.batcher.max_batch_events == 0 || self.batcher.max_buffered_events == 0 {
+            return Err(Error::Config(
+                "batcher event limits must be positive".to_string(),
+            ));
+        }
+        if self.batcher.retention_interval_seconds == 0 {
+            return Err(Error::Config(
+                "batcher retention_interval_seconds must be positive".to_string(),
+            ));
+        }
+        if self.batcher.max_wait_ms == 0
+            || self.batcher.max_wait_ms > MAX_WAIT_LIMIT.as_millis() as u64
+        {
+            return Err(Error::Config(format!(
+                "batcher max_wait_ms must be between 1 and {}",
+                MAX_WAIT_LIMIT.as_millis()
+            )));
+        }
+        calculator::CalculatorParameters::from_settings(&self.calculator)
+            .map_err(|e| Error::Config(format!("Invalid calculator settings: {}", e)))?;
+        Ok(())
+    }
+
+    /// The settings as TOML, with secrets redacted
+    pub fn to_toml(&self) -> Result<String, Error> {
+        let mut settings = self.clone();
+        if settings.did_private_key.is_some() {
+            settings.did_private_key = Some(REDACTED.to_string());
+        }
+        toml::to_string_pretty(&settings).map_err(|e| Error::Config(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = "6f1e7d9ea7c3a5b6e8f1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f7";
+
+    async fn load(contents: &str, overrides: Overrides) -> Result<Settings, Error> {
+        let dir = tmpdir::TmpDir::new("config").await.unwrap();
+        let path = dir.as_ref().join("checkpointer.toml");
+        std::fs::write(&path, contents).unwrap();
+        Settings::load(Some(&path), overrides)
+    }
+
+    #[tokio::test]
+    async fn overrides_take_precedence_over_file() {
+        let settings = load(
+            r#"
+bind_address = "127.0.0.1:9090"
+ceramic_url = "http://ceramic:7007/"
+database_url = "sqlite://file.db"
+shutdown_timeout_seconds = 5
+
+[auth]
+admin_dids = ["did:key:admin"]
+
+[batcher]
+max_batch_events = 10
+
+[calculator]
+referral_depth = 2
+
+[tracing]
+otlp_endpoint = "http://collector:4318/"
+"#,
+            Overrides {
+                database_url: Some("sqlite://flag.db".to_string()),
+                did_private_key: Some(PRIVATE_KEY.to_string()),
+                referral_depth: Some(4),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(settings.bind_address, "127.0.0.1:9090".parse().unwrap());
+        assert_eq!(settings.ceramic_url.as_str(), "http://ceramic:7007/");
+        assert_eq!(settings.database_url, "sqlite://flag.db");
+        assert_eq!(settings.shutdown_timeout_seconds, 5);
+        assert_eq!(settings.batcher.max_batch_events, 10);
+        assert_eq!(
+            settings.batcher.max_buffered_events,
+            DEFAULT_MAX_BUFFERED_EVENTS
+        );
+        assert_eq!(settings.calculator.referral_depth, 4);
+        assert_eq!(settings.auth.admin_dids, vec!["did:key:admin".to_string()]);
+        assert_eq!(
+            util::TracingOptions::from(&settings.tracing).otlp_endpoint,
+            Some("http://collector:4318/".to_string())
+        );
+        settings.validate().unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_settings() {
+        assert!(load("unknown = 1", Overrides::default()).await.is_err());
+        let settings = Settings::load(None, Overrides::default()).unwrap();
+        assert!(settings.validate().is_err());
+        let max_wait_ms = MAX_WAIT_LIMIT.as_millis() as u64;
+        let validate = |overrides: Overrides| {
+            Settings::load(
+                None,
+                Overrides {
+                    database_url: Some("sqlite://test.db".to_string()),
+                    did_private_key: Some(PRIVATE_KEY.to_string()),
+                    ..overrides
+                },
+            )
+            .unwrap()
+            .validate()
+        };
+        for overrides in [
+            Overrides {
+                referral_share_percent: Some(150),
+                ..Default::default()
+            },
+            Overrides {
+                admin_dids: Some(vec!["admin".to_string()]),
+                ..Default::default()
+            },
+            Overrides {
+                referral_depth: Some(calculator::MAX_REFERRAL_DEPTH + 1),
+                ..Default::default()
+            },
+            Overrides {
+                max_wait_ms: Some(0),
+                ..Default::default()
+            },
+            Overrides {
+                max_wait_ms: Some(max_wait_ms + 1),
+                ..Default::default()
+            },
+        ] {
+            assert!(validate(overrides).is_err());
+        }
+        validate(Overrides {
+            referral_depth: Some(calculator::MAX_REFERRAL_DEPTH),
+            max_wait_ms: Some(max_wait_ms),
+            ..Default::default()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn prints_settings_without_secrets() {
+        let settings = Settings::load(
+            None,
+            Overrides {
+                did_private_key: Some(PRIVATE_KEY.to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let printed = settings.to_toml().unwrap();
+        assert!(!printed.contains(PRIVATE_KEY));
+        let parsed: Settings = toml::from_str(&printed).unwrap();
+        assert_eq!(parsed.did_private_key.as_deref(), Some(REDACTED));
+        assert_eq!(parsed.batcher, settings.batcher);
+        assert_eq!(parsed.calculator, settings.calculator);
+    }
+}
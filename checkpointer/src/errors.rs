@@ -7,6 +7,10 @@ pub enum Error {
     Bind(#[from] std::io::Error),
     #[error("Batcher Not Found: {0}")]
     NotFound(String),
+    #[error("Feed for {0} could not resume from {1}, events may have been missed")]
+    FeedGap(String, String),
+    #[error("Batcher is not running")]
+    NotRunning,
     #[error("Failed to receive, shutting down")]
     Recv(#[from] tokio::sync::oneshot::error::RecvError),
     #[error("{0}")]
@@ -23,6 +27,14 @@ pub enum Error {
     Ceramic(#[from] anyhow::Error),
     #[error("{0}")]
     Reqwest(#[from] reqwest::Error),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Bad Request: {0}")]
+    BadRequest(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
     #[error("{0}")]
     Custom(String),
 }
@@ -35,6 +47,11 @@ impl Error {
 
 impl ResponseError for Error {
     fn status_code(&self) -> reqwest::StatusCode {
-        reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        match self {
+            Self::Unauthorized(_) => reqwest::StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => reqwest::StatusCode::FORBIDDEN,
+            Self::BadRequest(_) => reqwest::StatusCode::BAD_REQUEST,
+            _ => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
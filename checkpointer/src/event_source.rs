@@ -1,81 +1,192 @@
 use crate::errors::Error;
+use crate::metrics::metrics;
 use futures_util::StreamExt;
 use reqwest_eventsource::{Event as SseEvent, EventSource as ReqwestEventSource};
 use schema::Event;
+use std::future::Future;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::watch;
+use tracing::Instrument;
 use url::Url;
 
+const LAST_EVENT_ID: &str = "Last-Event-ID";
+
 pub struct EventSource {
     client_id: String,
     url: Url,
-    inner: ReqwestEventSource,
+    last_event_id: Option<String>,
+    /// Source of the feed, or `None` if the request could not be built, in which case it is
+    /// built again from `last_event_id` on the next attempt
+    inner: Option<ReqwestEventSource>,
+}
+
+/// An event along with its position in the upstream feed, if the feed provided one
+pub struct FeedEvent {
+    pub position: Option<String>,
+    pub event: Event,
 }
 
 pub struct RunningEventSource {
-    pub shutdown: Arc<AtomicBool>,
-    pub rx: tokio::sync::mpsc::Receiver<Result<Event, Error>>,
+    /// Stops the source once `true` is sent, even while it waits on the feed
+    pub shutdown: watch::Sender<bool>,
+    /// Whether the feed is currently connected, rather than waiting to reconnect
+    pub connected: Arc<AtomicBool>,
+    pub rx: tokio::sync::mpsc::Receiver<Result<FeedEvent, Error>>,
     pub join: actix_web::rt::task::JoinHandle<()>,
 }
 
 impl EventSource {
-    pub fn new(client_id: &str, ceramic_url: &Url) -> EventSource {
+    /// Create an event source for the ceramic feed, resuming after `last_event_id` if provided
+    pub fn new(client_id: &str, ceramic_url: &Url, last_event_id: Option<String>) -> EventSource {
         let ceramic_url = ceramic_url
             .join("/api/v0/feed/aggregation/documents")
             .unwrap();
         tracing::debug!(
-            "Creating event source against {} with client {}",
+            "Creating event source against {} with client {} from {:?}",
             ceramic_url,
-            client_id
+            client_id,
+            last_event_id
         );
+        let mut req = reqwest::Client::new().get(ceramic_url.clone());
+        if let Some(id) = last_event_id.as_ref() {
+            req = req.header(LAST_EVENT_ID, id);
+        }
+        let inner = match ReqwestEventSource::new(req) {
+            Ok(inner) => Some(inner),
+            Err(e) => {
+                tracing::error!(
+                    "Failed to create event source for client {} from {:?}: {:?}",
+                    client_id,
+                    last_event_id,
+                    e
+                );
+                None
+            }
+        };
         Self {
             client_id: client_id.to_string(),
-            url: ceramic_url.clone(),
-            inner: ReqwestEventSource::get(ceramic_url.to_string()),
+            url: ceramic_url,
+            last_event_id,
+            inner,
         }
     }
 
     pub fn run(self) -> RunningEventSource {
         let (tx, rx) = tokio::sync::mpsc::channel(100);
-        let shutdown = Arc::new(AtomicBool::new(false));
-        let join = tokio::spawn(run(self, tx, shutdown.clone()));
-        RunningEventSource { shutdown, rx, join }
+        let (shutdown, stopped) = watch::channel(false);
+        let connected = Arc::new(AtomicBool::new(false));
+        let join = tokio::spawn(run(self, tx, stopped, connected.clone()));
+        RunningEventSource {
+            shutdown,
+            connected,
+            rx,
+            join,
+        }
+    }
+
+    /// Whether the first event received after resuming shows that events were skipped
+    fn is_gap(&self, id: &str) -> bool {
+        match self.last_event_id.as_ref() {
+            None => false,
+            Some(_) if id.is_empty() => true,
+            Some(last) => match (last.parse::<u64>(), id.parse::<u64>()) {
+                (Ok(last), Ok(id)) => id > last + 1,
+                _ => false,
+            },
+        }
     }
 }
 
 async fn run(
     mut es: EventSource,
-    tx: tokio::sync::mpsc::Sender<Result<Event, Error>>,
-    shutdown: Arc<AtomicBool>,
+    tx: tokio::sync::mpsc::Sender<Result<FeedEvent, Error>>,
+    mut shutdown: watch::Receiver<bool>,
+    connected: Arc<AtomicBool>,
 ) {
     tracing::debug!("Running event source for client {}", es.client_id);
-    while !shutdown.load(Ordering::Relaxed) {
-        match es.inner.next().await {
+    let mut resuming = es.last_event_id.is_some();
+    while !*shutdown.borrow() {
+        let next = tokio::select! {
+            next = async {
+                match es.inner.as_mut() {
+                    Some(inner) => inner.next().await,
+                    None => None,
+                }
+            } => next,
+            _ = shutdown.changed() => break,
+        };
+        match next {
             Some(Ok(res)) => {
+                connected.store(true, Ordering::Relaxed);
                 if let SseEvent::Message(msg) = res {
+                    let gap = if resuming && es.is_gap(&msg.id) {
+                        es.last_event_id.clone()
+                    } else {
+                        None
+                    };
+                    resuming = false;
+                    let position = if msg.id.is_empty() {
+                        None
+                    } else {
+                        es.last_event_id = Some(msg.id.clone());
+                        Some(msg.id)
+                    };
                     match serde_json::from_str::<Event>(&msg.data) {
                         Ok(event) => {
-                            if tx.send(Ok(event)).await.is_err() {
+                            // spans the wait for the batcher to take the event
+                            let span = util::event_span("receive_event", &event.commit_id);
+                            let send = tx.send(Ok(FeedEvent { position, event }));
+                            if !unless_shutdown(send.instrument(span), &mut shutdown).await {
                                 return;
                             }
                         }
                         Err(e) => {
-                            if tx.send(Err(Error::Json(e))).await.is_err() {
+                            let send = tx.send(Err(Error::Json(e)));
+                            if !unless_shutdown(send, &mut shutdown).await {
                                 return;
                             }
                         }
                     }
+                    if let Some(position) = gap {
+                        tracing::warn!(
+                            "Event source for client {} could not resume from {}",
+                            es.client_id,
+                            position
+                        );
+                        let err = Error::FeedGap(es.client_id.clone(), position);
+                        if !unless_shutdown(tx.send(Err(err)), &mut shutdown).await {
+                            return;
+                        }
+                    }
                 }
             }
             r => {
+                connected.store(false, Ordering::Relaxed);
                 if let Some(Err(e)) = r {
                     tracing::warn!("Event source for client {} failed: {:?}", es.client_id, e);
                 }
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                    _ = shutdown.changed() => break,
+                }
                 tracing::info!("Reforming event source for client {}", es.client_id);
-                es = EventSource::new(&es.client_id, &es.url);
+                metrics().feed_reconnects.inc();
+                es = EventSource::new(&es.client_id, &es.url, es.last_event_id.clone());
+                resuming = es.last_event_id.is_some();
             }
         }
     }
 }
+
+/// Wait for `send` unless shutdown is signalled first, returning whether the source should go on
+async fn unless_shutdown<T, E>(
+    send: impl Future<Output = Result<T, E>>,
+    shutdown: &mut watch::Receiver<bool>,
+) -> bool {
+    tokio::select! {
+        res = send => res.is_ok(),
+        _ = shutdown.changed() => false,
+    }
+}
@@ -1,31 +1,56 @@
+use actix_web::http::header::ContentEncoding;
 use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers, Logger};
 use actix_web::{
-    delete, dev::ServiceResponse, get, http::StatusCode, post, web, App, HttpResponse, HttpServer,
-    Responder, Result,
+    delete,
+    dev::{ServerHandle, ServiceResponse},
+    get,
+    http::StatusCode,
+    post, web, App, HttpRequest, HttpResponse, HttpServer, Responder, Result,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing_actix_web::TracingLogger;
 
+mod auth;
 mod batcher;
 mod calculator;
 mod ceramic;
+mod config;
 mod errors;
 mod event_source;
+mod metrics;
 mod persistence;
+mod streaming;
+mod tokens;
 
-use crate::persistence::SqlitePersistence;
+use auth::{Authenticator, Caller};
 use batcher::{BatchCreationParameters, Batcher};
 use calculator::CalculatorParameters;
 use clap::{Parser, Subcommand};
+use config::{Overrides, Settings};
 use errors::Error;
+use persistence::BatchLimit;
+use schema::Ack;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use streaming::StreamFormat;
+use tokens::TokenIssuer;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
 #[derive(Parser)]
 #[command(name = "CeramicCheckpointer")]
 #[command(version = "1.0")]
 #[command(about = "Provides batching and checkpointing for ceramic sse feeds", long_about = None)]
 struct Cli {
+    /// TOML file to read settings from
+    #[arg(long, env = "CHECKPOINTER_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    overrides: Overrides,
     #[clap(subcommand)]
     subcmd: Option<SubCmd>,
 }
@@ -33,6 +58,17 @@ struct Cli {
 #[derive(Subcommand)]
 enum SubCmd {
     SshCheck,
+    /// Inspect the checkpointer's configuration
+    Config {
+        #[clap(subcommand)]
+        subcmd: ConfigCmd,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCmd {
+    /// Print the effective configuration as TOML, with secrets redacted
+    Print,
 }
 
 fn trace_error<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
@@ -43,13 +79,25 @@ fn trace_error<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
     Ok(ErrorHandlerResponse::Response(res.map_into_left_body()))
 }
 
+/// Check that the caller may use a client's batcher
+async fn authorize(config: &Config, caller: &Caller, client_id: &str) -> Result<(), Error> {
+    let owner = config.batcher.owner(client_id).await?;
+    caller.require_owner(client_id, owner.as_deref())
+}
+
 #[post("/batch")]
 pub async fn create_batcher(
     config: web::Data<Config>,
+    caller: Caller,
     data: web::Json<BatchCreationParameters>,
 ) -> Result<impl Responder, Error> {
-    let res = match config.batcher.create_batcher(data.into_inner()).await {
+    let res = match config
+        .batcher
+        .create_batcher(data.into_inner(), &caller)
+        .await
+    {
         Ok(_) => HttpResponse::Ok().finish(),
+        Err(Error::Forbidden(reason)) => HttpResponse::Forbidden().body(reason),
         Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     };
     Ok(res)
@@ -58,38 +106,178 @@ pub async fn create_batcher(
 #[derive(Debug, Deserialize)]
 pub struct BatchQueryParameters {
     pub limit: Option<usize>,
+    /// Stop adding events once the batch reaches this size. Later events are returned by the
+    /// next request.
+    pub max_bytes: Option<usize>,
+    pub wait_ms: Option<u64>,
 }
 
 #[get("/batch/{client_id}")]
 pub async fn get_batch(
     config: web::Data<Config>,
+    caller: Caller,
     client_id: web::Path<String>,
     query: web::Query<BatchQueryParameters>,
 ) -> Result<impl Responder, Error> {
-    let res = config.batcher.get_batch(&client_id, query.limit).await;
+    let res = match authorize(&config, &caller, &client_id).await {
+        Ok(()) => {
+            config
+                .batcher
+                .get_batch(
+                    &client_id,
+                    BatchLimit {
+                        max_events: query.limit,
+                        max_bytes: query.max_bytes,
+                    },
+                    query.wait_ms.map(Duration::from_millis),
+                )
+                .await
+        }
+        Err(e) => Err(e),
+    };
     let res = match res {
         Ok(res) => {
-            tracing::info!("Events:{:?}", res);
+            tracing::info!("Events:{:?}", res.events);
             HttpResponse::Ok().json(res)
         }
         Err(Error::NotFound(id)) => {
             HttpResponse::NotFound().body(format!("Batcher {} not found", id))
         }
+        Err(Error::Forbidden(reason)) => HttpResponse::Forbidden().body(reason),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    Ok(res)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQueryParameters {
+    pub cursor: Option<i64>,
+    #[serde(default)]
+    pub format: StreamFormat,
+}
+
+#[get("/batch/{client_id}/stream")]
+pub async fn stream_batch(
+    config: web::Data<Config>,
+    caller: Caller,
+    client_id: web::Path<String>,
+    query: web::Query<StreamQueryParameters>,
+    req: HttpRequest,
+) -> Result<impl Responder, Error> {
+    // SSE consumers resume from the sequence of the last event they saw
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let cursor = last_event_id.or(query.cursor).unwrap_or(0);
+    let client_id = client_id.into_inner();
+    let res = match authorize(&config, &caller, &client_id).await {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(query.format.content_type())
+            // compression would buffer the stream
+            .insert_header(ContentEncoding::Identity)
+            .streaming(streaming::batch_stream(
+                config.batcher.clone(),
+                client_id,
+                cursor,
+                query.format,
+            )),
+        Err(Error::NotFound(id)) => {
+            HttpResponse::NotFound().body(format!("Batcher {} not found", id))
+        }
+        Err(Error::Forbidden(reason)) => HttpResponse::Forbidden().body(reason),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    Ok(res)
+}
+
+#[post("/batch/{client_id}/ack")]
+pub async fn ack_batch(
+    config: web::Data<Config>,
+    caller: Caller,
+    client_id: web::Path<String>,
+    data: web::Json<Ack>,
+) -> Result<impl Responder, Error> {
+    let res = match authorize(&config, &caller, &client_id).await {
+        Ok(()) => config.batcher.ack(&client_id, data.cursor).await,
+        Err(e) => Err(e),
+    };
+    let res = match res {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(Error::NotFound(id)) => {
+            HttpResponse::NotFound().body(format!("Batcher {} not found", id))
+        }
+        Err(Error::Forbidden(reason)) => HttpResponse::Forbidden().body(reason),
+        Err(Error::BadRequest(reason)) => HttpResponse::BadRequest().body(reason),
         Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     };
     Ok(res)
 }
 
+/// Statuses of the batchers the caller owns, or of every batcher for admins
+#[get("/batch")]
+pub async fn list_batchers(
+    config: web::Data<Config>,
+    caller: Caller,
+) -> Result<impl Responder, Error> {
+    let mut statuses = config.batcher.list().await?;
+    statuses.retain(|status| caller.can_act_for(status.owner.as_deref()));
+    Ok(HttpResponse::Ok().json(statuses))
+}
+
+#[get("/batch/{client_id}/status")]
+pub async fn batcher_status(
+    config: web::Data<Config>,
+    caller: Caller,
+    client_id: web::Path<String>,
+) -> Result<impl Responder, Error> {
+    let res = config.batcher.status(&client_id).await.and_then(|status| {
+        caller.require_owner(&client_id, status.owner.as_deref())?;
+        Ok(status)
+    });
+    let res = match res {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(Error::NotFound(id)) => {
+            HttpResponse::NotFound().body(format!("Batcher {} not found", id))
+        }
+        Err(Error::Forbidden(reason)) => HttpResponse::Forbidden().body(reason),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    Ok(res)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteQueryParameters {
+    pub archive: Option<bool>,
+}
+
 #[delete("/batch/{client_id}")]
 pub async fn delete_batcher(
-    _config: web::Data<Config>,
-    _client_id: web::Path<String>,
+    config: web::Data<Config>,
+    caller: Caller,
+    client_id: web::Path<String>,
+    query: web::Query<DeleteQueryParameters>,
 ) -> Result<impl Responder, Error> {
-    Ok(HttpResponse::Ok().finish())
+    let archive = query.archive.unwrap_or(false);
+    let res = match authorize(&config, &caller, &client_id).await {
+        Ok(()) => config.batcher.delete_batcher(&client_id, archive).await,
+        Err(e) => Err(e),
+    };
+    let res = match res {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(Error::NotFound(id)) => {
+            HttpResponse::NotFound().body(format!("Batcher {} not found", id))
+        }
+        Err(Error::Forbidden(reason)) => HttpResponse::Forbidden().body(reason),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    Ok(res)
 }
 
 #[post("/calculate")]
-pub async fn calculate(config: web::Data<Config>) -> Result<impl Responder, Error> {
+pub async fn calculate(config: web::Data<Config>, caller: Caller) -> Result<impl Responder, Error> {
+    caller.require_admin()?;
     if config
         .calculate_active
         .load(std::sync::atomic::Ordering::Relaxed)
@@ -101,11 +289,45 @@ pub async fn calculate(config: web::Data<Config>) -> Result<impl Responder, Erro
             .calculate_active
             .store(true, std::sync::atomic::Ordering::Relaxed);
         let calculator = calculator::Calculator::new(config.calculator_params.clone())?;
-        calculator.run();
+        let task = calculator.run(config.shutdown.subscribe());
+        *config.calculator.lock().unwrap() = Some(task);
         Ok(HttpResponse::Ok().finish())
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+}
+
+#[get("/points/{holder}/token")]
+pub async fn issue_token(
+    config: web::Data<Config>,
+    caller: Caller,
+    holder: web::Path<String>,
+) -> Result<impl Responder, Error> {
+    // a token is a bearer credential for its holder, so only the holder may request one
+    if !caller.can_act_for(Some(&holder)) {
+        return Ok(HttpResponse::Forbidden().body(format!(
+            "{} may not request tokens for {}",
+            caller.did, holder
+        )));
+    }
+    let token = config.tokens.issue(&holder).await?;
+    Ok(HttpResponse::Ok().json(TokenResponse { token }))
+}
+
+/// Metrics in the Prometheus text format
+#[get("/metrics")]
+pub async fn export_metrics() -> Result<impl Responder, Error> {
+    let text = metrics::metrics()
+        .encode()
+        .map_err(|e| Error::custom(e.to_string()))?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(text))
+}
+
 #[get("/healthcheck")]
 pub async fn healthcheck() -> impl Responder {
     HttpResponse::Ok().finish()
@@ -116,15 +338,31 @@ pub struct Config {
     batcher: Batcher,
     calculator_params: CalculatorParameters,
     calculate_active: Arc<AtomicBool>,
+    /// The running calculator, awaited on shutdown so its writes are not cut off
+    calculator: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Signals the calculator to stop
+    shutdown: Arc<watch::Sender<bool>>,
+    tokens: Arc<TokenIssuer>,
 }
 
 #[actix_web::main]
 async fn main() -> Result<(), Error> {
-    let _guard = util::init_tracing();
     let cmd = Cli::parse();
+    let settings = Settings::load(cmd.config.as_deref(), cmd.overrides)?;
+    let _guard = util::init_tracing_with((&settings.tracing).into());
 
-    let calculator_params = CalculatorParameters::new().await?;
+    if let Some(SubCmd::Config {
+        subcmd: ConfigCmd::Print,
+    }) = cmd.subcmd
+    {
+        print!("{}", settings.to_toml()?);
+        return settings.validate();
+    }
+    settings.validate()?;
+
+    let calculator_params = CalculatorParameters::new(&settings).await?;
     match cmd.subcmd {
+        Some(SubCmd::Config { .. }) => {}
         Some(SubCmd::SshCheck) => {
             let url = calculator_params
                 .ceramic_url
@@ -134,25 +372,52 @@ async fn main() -> Result<(), Error> {
             }
         }
         None => {
+            let tokens = TokenIssuer::new(&settings, &calculator_params)?;
             let config = Config {
-                batcher: Batcher::new(Arc::new(SqlitePersistence::new().await?))?,
+                batcher: Batcher::new(
+                    persistence::connect(&settings.database_url).await?,
+                    &settings,
+                )?,
                 calculator_params,
+                tokens: Arc::new(tokens),
                 calculate_active: Arc::new(AtomicBool::new(false)),
+                calculator: Arc::new(Mutex::new(None)),
+                shutdown: Arc::new(watch::channel(false).0),
             };
-            start_server(config).await?;
+            let auth = Authenticator::new(&settings.auth);
+            start_server(
+                config,
+                auth,
+                settings.bind_address,
+                Duration::from_secs(settings.shutdown_timeout_seconds),
+            )
+            .await?;
         }
     }
 
     Ok(())
 }
 
-async fn start_server(config: Config) -> Result<(), Error> {
-    HttpServer::new(move || {
+async fn start_server(
+    config: Config,
+    auth: Authenticator,
+    bind_address: SocketAddr,
+    shutdown_timeout: Duration,
+) -> Result<(), Error> {
+    tracing::info!("Listening on {}", bind_address);
+    let auth = web::Data::new(auth);
+    let app_config = config.clone();
+    let server = HttpServer::new(move || {
         let svc = web::scope("/api/v1")
             .service(create_batcher)
+            .service(list_batchers)
+            .service(batcher_status)
             .service(get_batch)
+            .service(stream_batch)
+            .service(ack_batch)
             .service(delete_batcher)
             .service(calculate)
+            .service(issue_token)
             .service(healthcheck);
         App::new()
             .wrap(TracingLogger::default())
@@ -161,12 +426,62 @@ async fn start_server(config: Config) -> Result<(), Error> {
             .wrap(Logger::new(
                 "%a %t \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T",
             ))
-            .app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(app_config.clone()))
+            .app_data(auth.clone())
             .service(svc)
+            .service(export_metrics)
     })
-    .bind(("0.0.0.0", 8080))
+    .bind(bind_address)
     .map_err(errors::Error::Bind)?
-    .run()
-    .await?;
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs())
+    .run();
+    let handle = server.handle();
+    let mut serving = actix_web::rt::spawn(server);
+    tokio::select! {
+        res = &mut serving => {
+            return Ok(res.map_err(|e| Error::custom(e.to_string()))??);
+        }
+        res = shutdown_signal() => res?,
+    }
+
+    tracing::info!("Shutting down");
+    if tokio::time::timeout(shutdown_timeout, shutdown(&config, handle))
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Shutdown did not finish within {} seconds",
+            shutdown_timeout.as_secs()
+        );
+    }
     Ok(())
 }
+
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Stop accepting requests, store buffered events and let the calculator finish its current
+/// event before exiting
+async fn shutdown(config: &Config, server: ServerHandle) {
+    // stopping the server waits for open requests, which the batcher answers as it shuts down
+    let stopped = server.stop(true);
+    config.shutdown.send_replace(true);
+    if let Err(e) = config.batcher.shutdown().await {
+        tracing::error!("Failed to shut down batcher: {}", e);
+    }
+    let calculator = config.calculator.lock().unwrap().take();
+    if let Some(calculator) = calculator {
+        if let Err(e) = calculator.await {
+            tracing::error!("Calculator failed: {}", e);
+        }
+    }
+    stopped.await;
+    tracing::info!("Shutdown complete");
+}
@@ -0,0 +1,240 @@
+use calculator::CalculatorMetrics;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use schema::DroppedEvents;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+const NAMESPACE: &str = "checkpointer";
+
+/// Metrics exported at `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    /// Events stored or buffered for each client
+    pub events_received: IntCounterVec,
+    /// Times an upstream feed was reconnected after failing or ending
+    pub feed_reconnects: IntCounter,
+    /// Failed writes to the database, by operation
+    pub persistence_failures: IntCounterVec,
+    /// Events waiting to be persisted for each client
+    pub buffered_events: IntGaugeVec,
+    /// Stored events dropped by each client's retention policy, by reason
+    pub events_dropped: IntCounterVec,
+    /// Time taken to read a polled batch from storage, excluding any wait for events
+    pub get_batch_seconds: Histogram,
+    /// Counts shared with every calculator the checkpointer runs
+    pub calculator: Arc<CalculatorMetrics>,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// The checkpointer's metrics, registered on first use
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("metrics should be valid"))
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None)?;
+        let events_received = IntCounterVec::new(
+            Opts::new("events_received_total", "Events received per client"),
+            &["client_id"],
+        )?;
+        let feed_reconnects = IntCounter::new(
+            "feed_reconnects_total",
+            "Reconnects to the upstream ceramic feed",
+        )?;
+        let persistence_failures = IntCounterVec::new(
+            Opts::new("persistence_failures_total", "Failed database writes"),
+            &["operation"],
+        )?;
+        let buffered_events = IntGaugeVec::new(
+            Opts::new(
+                "buffered_events",
+                "Events waiting to be persisted per client",
+            ),
+            &["client_id"],
+        )?;
+        let events_dropped = IntCounterVec::new(
+            Opts::new(
+                "events_dropped_total",
+                "Stored events dropped by retention per client and reason",
+            ),
+            &["client_id", "reason"],
+        )?;
+        let get_batch_seconds = Histogram::with_opts(HistogramOpts::new(
+            "get_batch_seconds",
+            "Latency of reading polled batches from storage, excluding waits for events",
+        ))?;
+        let calculator = Arc::<CalculatorMetrics>::default();
+        registry.register(Box::new(events_received.clone()))?;
+        registry.register(Box::new(feed_reconnects.clone()))?;
+        registry.register(Box::new(persistence_failures.clone()))?;
+        registry.register(Box::new(buffered_events.clone()))?;
+        registry.register(Box::new(events_dropped.clone()))?;
+        registry.register(Box::new(get_batch_seconds.clone()))?;
+        registry.register(Box::new(CalculatorCollector::new(calculator.clone())?))?;
+        Ok(Self {
+            registry,
+            events_received,
+            feed_reconnects,
+            persistence_failures,
+            buffered_events,
+            events_dropped,
+            get_batch_seconds,
+            calculator,
+        })
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buf = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        String::from_utf8(buf).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+
+    /// Count the events a client's retention policy dropped
+    pub fn record_dropped(&self, client_id: &str, dropped: &DroppedEvents) {
+        for (reason, count) in dropped_reasons(dropped) {
+            self.events_dropped
+                .with_label_values(&[client_id, reason])
+                .inc_by(count);
+        }
+    }
+
+    /// Stop reporting a deleted client
+    pub fn remove_client(&self, client_id: &str) {
+        let _ = self.events_received.remove_label_values(&[client_id]);
+        let _ = self.buffered_events.remove_label_values(&[client_id]);
+        for (reason, _) in dropped_reasons(&DroppedEvents::default()) {
+            let _ = self
+                .events_dropped
+                .remove_label_values(&[client_id, reason]);
+        }
+    }
+}
+
+fn dropped_reasons(dropped: &DroppedEvents) -> [(&'static str, u64); 3] {
+    [
+        ("expired", dropped.expired),
+        ("evicted", dropped.evicted),
+        ("compacted", dropped.compacted),
+    ]
+}
+
+/// Exports the calculator's counters, which the calculator crate keeps without depending on
+/// prometheus
+struct CalculatorCollector {
+    metrics: Arc<CalculatorMetrics>,
+    events: IntCounterVec,
+    materializations: IntCounterVec,
+}
+
+impl CalculatorCollector {
+    fn new(metrics: Arc<CalculatorMetrics>) -> Result<Self, prometheus::Error> {
+        Ok(Self {
+            metrics,
+            events: IntCounterVec::new(
+                Opts::new("calculator_events_total", "Events seen by the calculator"),
+                &["outcome"],
+            )?,
+            materializations: IntCounterVec::new(
+                Opts::new(
+                    "calculator_materializations_total",
+                    "Point materializations written to ceramic",
+                ),
+                &["operation"],
+            )?,
+        })
+    }
+}
+
+impl Collector for CalculatorCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.events
+            .desc()
+            .into_iter()
+            .chain(self.materializations.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        fn set(vec: &IntCounterVec, label: &str, value: &AtomicU64) {
+            let counter = vec.with_label_values(&[label]);
+            counter.reset();
+            counter.inc_by(value.load(Ordering::Relaxed));
+        }
+        let m = &self.metrics;
+        set(&self.events, "processed", &m.events_processed);
+        set(&self.events, "skipped", &m.events_skipped);
+        set(&self.events, "failed", &m.events_failed);
+        set(
+            &self.materializations,
+            "create",
+            &m.materializations_created,
+        );
+        set(
+            &self.materializations,
+            "update",
+            &m.materializations_updated,
+        );
+        self.events
+            .collect()
+            .into_iter()
+            .chain(self.materializations.collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_metrics_as_text() {
+        let metrics = Metrics::new().unwrap();
+        metrics
+            .events_received
+            .with_label_values(&["client"])
+            .inc_by(2);
+        metrics.feed_reconnects.inc();
+        metrics
+            .buffered_events
+            .with_label_values(&["client"])
+            .set(3);
+        metrics.get_batch_seconds.observe(0.5);
+        metrics.record_dropped(
+            "client",
+            &DroppedEvents {
+                expired: 1,
+                evicted: 2,
+                compacted: 0,
+            },
+        );
+        metrics
+            .calculator
+            .events_skipped
+            .fetch_add(4, Ordering::Relaxed);
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"checkpointer_events_received_total{client_id="client"} 2"#));
+        assert!(text.contains("checkpointer_feed_reconnects_total 1"));
+        assert!(text.contains(r#"checkpointer_buffered_events{client_id="client"} 3"#));
+        assert!(text.contains("checkpointer_get_batch_seconds_count 1"));
+        assert!(text.contains(
+            r#"checkpointer_events_dropped_total{client_id="client",reason="evicted"} 2"#
+        ));
+        assert!(text.contains(
+            r#"checkpointer_events_dropped_total{client_id="client",reason="compacted"} 0"#
+        ));
+        assert!(text.contains(r#"checkpointer_calculator_events_total{outcome="skipped"} 4"#));
+        assert!(text
+            .contains(r#"checkpointer_calculator_materializations_total{operation="create"} 0"#));
+
+        metrics.remove_client("client");
+        assert!(!metrics.encode().unwrap().contains("client_id=\"client\""));
+    }
+}
@@ -1,18 +1,128 @@
 use crate::Error;
-use schema::Event;
-use sqlx::{migrate::MigrateDatabase, Acquire, Connection, Sqlite};
+use ceramic_http_client::ceramic_event::StreamId;
+use schema::{DroppedEvents, Event, EventFilter, RetentionPolicy, SequencedEvent};
+use sqlx::{migrate::MigrateDatabase, Sqlite};
+use std::str::FromStr;
+use std::sync::Arc;
+
+mod postgres;
+
+pub use postgres::PostgresPersistence;
+
+/// Connect to the database at `url`, using postgres for `postgres://` urls and sqlite otherwise
+pub async fn connect(url: &str) -> Result<Arc<dyn Persistence + Send + Sync>, Error> {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Arc::new(PostgresPersistence::new_with_url(url).await?))
+    } else {
+        Ok(Arc::new(SqlitePersistence::new_with_url(url).await?))
+    }
+}
 
 #[derive(sqlx::FromRow)]
 pub struct EventRow {
-    pub id: String,
-    pub client_id: String,
+    pub seq: i64,
     pub event: sqlx::types::Json<Event>,
 }
 
+impl From<EventRow> for SequencedEvent {
+    fn from(row: EventRow) -> Self {
+        Self {
+            sequence: row.seq,
+            event: row.event.0,
+        }
+    }
+}
+
+/// Bounds on the events returned by a single read
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BatchLimit {
+    pub max_events: Option<usize>,
+    /// Reading stops once the events read reach this many bytes. At least one event is always
+    /// returned, so a single large event may exceed it.
+    pub max_bytes: Option<usize>,
+}
+
+impl BatchLimit {
+    fn max_bytes(&self) -> i64 {
+        self.max_bytes.map_or(i64::MAX, |b| b as i64)
+    }
+}
+
+/// Stream an event belongs to, which compaction keeps one event for. Commit ids parse to the id
+/// of their stream.
+fn stream_id(event: &Event) -> String {
+    StreamId::from_str(&event.commit_id)
+        .map_or_else(|_| event.commit_id.clone(), |id| id.to_string())
+}
+
+/// A client's batcher, restored when the checkpointer starts
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registration {
+    pub client_id: String,
+    pub filter: EventFilter,
+    pub retention: RetentionPolicy,
+    pub created_at: i64,
+    /// Highest sequence assigned to the client's events
+    pub last_sequence: i64,
+    /// DID the client is bound to, which is `None` for clients registered before authentication
+    pub owner: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct RegistrationRow {
+    client_id: String,
+    filter: sqlx::types::Json<EventFilter>,
+    retention: sqlx::types::Json<RetentionPolicy>,
+    created_at: i64,
+    last_sequence: i64,
+    owner: Option<String>,
+}
+
+impl From<RegistrationRow> for Registration {
+    fn from(row: RegistrationRow) -> Self {
+        Self {
+            client_id: row.client_id,
+            filter: row.filter.0,
+            retention: row.retention.0,
+            created_at: row.created_at,
+            last_sequence: row.last_sequence,
+            owner: row.owner,
+        }
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Persistence {
-    async fn add_event(&self, client_id: &str, event: &Event) -> Result<(), Error>;
-    async fn get_events(&self, client_id: &str) -> Result<Vec<Event>, Error>;
+    /// Store an event under the sequence assigned to it, ignoring events the client already holds.
+    /// Returns whether the event was stored, and so whether its sequence was taken.
+    async fn add_event(&self, client_id: &str, event: &SequencedEvent) -> Result<bool, Error>;
+    /// Events for a client with a sequence after `cursor`, in sequence order and within `limit`
+    async fn get_events(
+        &self,
+        client_id: &str,
+        cursor: i64,
+        limit: BatchLimit,
+    ) -> Result<Vec<SequencedEvent>, Error>;
+    /// Remove the events for a client up to and including `cursor`
+    async fn ack_events(&self, client_id: &str, cursor: i64) -> Result<(), Error>;
+    /// Number of stored events for a client that have not been acknowledged
+    async fn count_events(&self, client_id: &str) -> Result<i64, Error>;
+    /// Record the last position in a ceramic feed whose events have been stored for every client
+    async fn set_feed_position(&self, feed: &str, position: &str) -> Result<(), Error>;
+    async fn get_feed_position(&self, feed: &str) -> Result<Option<String>, Error>;
+    /// Save a registration. If the client is already registered only its filter and retention
+    /// are updated, and its owner is set if it has none.
+    async fn add_registration(&self, registration: &Registration) -> Result<(), Error>;
+    async fn get_registrations(&self) -> Result<Vec<Registration>, Error>;
+    /// Remove all stored state for a client, moving its events to the archive if `archive` is set
+    async fn delete_client(&self, client_id: &str, archive: bool) -> Result<(), Error>;
+    /// Drop the client's events that fall outside `policy` as of `now`, a unix timestamp
+    async fn apply_retention(
+        &self,
+        client_id: &str,
+        policy: &RetentionPolicy,
+        now: i64,
+    ) -> Result<DroppedEvents, Error>;
 }
 
 #[derive(Clone)]
@@ -21,12 +131,6 @@ pub struct SqlitePersistence {
 }
 
 impl SqlitePersistence {
-    pub async fn new() -> Result<Self, Error> {
-        let url =
-            std::env::var("DATABASE_URL").map_err(|_| Error::custom("DATABASE_URL was not set"))?;
-        Self::new_with_url(&url).await
-    }
-
     pub async fn new_with_url(db: &str) -> Result<Self, Error> {
         tracing::info!("Using database at {}", db);
         let database_exists = Sqlite::database_exists(db).await?;
@@ -35,60 +139,221 @@ impl SqlitePersistence {
             Sqlite::create_database(db).await?;
         }
         let pool = sqlx::sqlite::SqlitePool::connect(db).await?;
-        if !database_exists {
+        sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistence for SqlitePersistence {
+    async fn add_event(&self, client_id: &str, event: &SequencedEvent) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+        // an event already stored for this client is a redelivery from the feed
+        let added = sqlx::query(
+            "INSERT INTO events (client_id, seq, id, event, created_at, stream_id)
+VALUES (?, ?, ?, ?, ?, ?)
+ON CONFLICT (client_id, id) DO NOTHING",
+        )
+        .bind(client_id)
+        .bind(event.sequence)
+        .bind(&event.event.commit_id)
+        .bind(sqlx::types::Json(&event.event))
+        .bind(chrono::Utc::now().timestamp())
+        .bind(stream_id(&event.event))
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            == 1;
+        if added {
             sqlx::query(
-                "CREATE TABLE IF NOT EXISTS events
-(
-    id          TEXT PRIMARY KEY NOT NULL,
-    client_id   TEXT             NOT NULL,
-    event       JSONB            NOT NULL
-);",
+                "UPDATE registrations SET last_sequence = ? WHERE client_id = ? AND last_sequence < ?",
             )
-            .execute(&pool)
+            .bind(event.sequence)
+            .bind(client_id)
+            .bind(event.sequence)
+            .execute(&mut *tx)
             .await?;
         }
-        Ok(Self { pool })
+        tx.commit().await?;
+        Ok(added)
     }
 
-    async fn acquire(&self) -> Result<sqlx::pool::PoolConnection<Sqlite>, Error> {
-        Ok(self.pool.acquire().await?)
+    async fn get_events(
+        &self,
+        client_id: &str,
+        cursor: i64,
+        limit: BatchLimit,
+    ) -> Result<Vec<SequencedEvent>, Error> {
+        // keep each event whose preceding events are still under the byte limit
+        let rows = sqlx::query_as::<_, EventRow>(
+            "SELECT seq, event FROM (
+    SELECT seq, event, LENGTH(CAST(event AS BLOB)) AS size,
+        SUM(LENGTH(CAST(event AS BLOB))) OVER (ORDER BY seq) AS total
+    FROM events WHERE client_id = ? AND seq > ?
+) WHERE total - size < ? ORDER BY seq LIMIT ?",
+        )
+        .bind(client_id)
+        .bind(cursor)
+        .bind(limit.max_bytes())
+        // a negative limit is no limit in sqlite
+        .bind(limit.max_events.map_or(-1, |n| n as i64))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(SequencedEvent::from).collect())
     }
-}
 
-#[async_trait::async_trait]
-impl Persistence for SqlitePersistence {
-    async fn add_event(&self, client_id: &str, event: &Event) -> Result<(), Error> {
-        sqlx::query("INSERT INTO events (id, client_id, event) VALUES (?, ?, ?)")
-            .bind(event.commit_id.clone())
+    async fn ack_events(&self, client_id: &str, cursor: i64) -> Result<(), Error> {
+        sqlx::query("DELETE FROM events WHERE client_id = ? AND seq <= ?")
             .bind(client_id)
-            .bind(sqlx::types::Json(event))
+            .bind(cursor)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    async fn get_events(&self, client_id: &str) -> Result<Vec<Event>, Error> {
-        let mut conn = self.acquire().await?;
-        let client_id = client_id.to_string();
-        let events: Result<Vec<_>, sqlx::Error> = conn
-            .transaction(|txn| {
-                Box::pin(async move {
-                    let conn = txn.acquire().await?;
-                    let events =
-                        sqlx::query_as::<_, EventRow>("SELECT * FROM events WHERE client_id = ?")
-                            .bind(&client_id)
-                            .fetch_all(conn)
-                            .await?;
-                    let conn = txn.acquire().await?;
-                    sqlx::query("DELETE FROM events WHERE client_id = ?")
-                        .bind(client_id)
-                        .execute(conn)
-                        .await?;
-                    Ok(events.into_iter().map(|ev| ev.event.0).collect())
-                })
-            })
-            .await;
-        Ok(events?)
+    async fn set_feed_position(&self, feed: &str, position: &str) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO feed_positions (feed, position) VALUES (?, ?)
+ON CONFLICT (feed) DO UPDATE SET position = excluded.position",
+        )
+        .bind(feed)
+        .bind(position)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_feed_position(&self, feed: &str) -> Result<Option<String>, Error> {
+        let position: Option<(String,)> =
+            sqlx::query_as("SELECT position FROM feed_positions WHERE feed = ?")
+                .bind(feed)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(position.map(|(p,)| p))
+    }
+
+    async fn count_events(&self, client_id: &str) -> Result<i64, Error> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM events WHERE client_id = ?")
+            .bind(client_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    async fn add_registration(&self, registration: &Registration) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO registrations (client_id, filter, retention, created_at, last_sequence, owner)
+VALUES (?, ?, ?, ?, ?, ?)
+ON CONFLICT (client_id) DO UPDATE SET filter = excluded.filter, retention = excluded.retention,
+    owner = COALESCE(registrations.owner, excluded.owner)",
+        )
+        .bind(&registration.client_id)
+        .bind(sqlx::types::Json(&registration.filter))
+        .bind(sqlx::types::Json(&registration.retention))
+        .bind(registration.created_at)
+        .bind(registration.last_sequence)
+        .bind(&registration.owner)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_registrations(&self) -> Result<Vec<Registration>, Error> {
+        let rows = sqlx::query_as::<_, RegistrationRow>(
+            "SELECT client_id, filter, retention, created_at, last_sequence, owner FROM registrations
+ORDER BY client_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Registration::from).collect())
+    }
+
+    async fn delete_client(&self, client_id: &str, archive: bool) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        if archive {
+            sqlx::query(
+                "INSERT INTO archived_events (seq, id, client_id, event, archived_at)
+SELECT seq, id, client_id, event, ? FROM events WHERE client_id = ?",
+            )
+            .bind(chrono::Utc::now().timestamp())
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("DELETE FROM events WHERE client_id = ?")
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM registrations WHERE client_id = ?")
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn apply_retention(
+        &self,
+        client_id: &str,
+        policy: &RetentionPolicy,
+        now: i64,
+    ) -> Result<DroppedEvents, Error> {
+        let mut dropped = DroppedEvents::default();
+        let mut tx = self.pool.begin().await?;
+        // compact first so the count and size limits apply to the events that remain
+        if policy.compact {
+            dropped.compacted = sqlx::query(
+                "DELETE FROM events WHERE client_id = ? AND seq < (
+    SELECT MAX(seq) FROM events AS latest
+    WHERE latest.client_id = events.client_id AND latest.stream_id = events.stream_id
+)",
+            )
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        if let Some(max_age) = policy.max_age_seconds {
+            dropped.expired =
+                sqlx::query("DELETE FROM events WHERE client_id = ? AND created_at < ?")
+                    .bind(client_id)
+                    .bind(now.saturating_sub(max_age as i64))
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+        }
+        if let Some(max_events) = policy.max_events {
+            dropped.evicted += sqlx::query(
+                "DELETE FROM events WHERE client_id = ? AND seq <= (
+    SELECT seq FROM events WHERE client_id = ? ORDER BY seq DESC LIMIT 1 OFFSET ?
+)",
+            )
+            .bind(client_id)
+            .bind(client_id)
+            .bind(max_events as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        if let Some(max_bytes) = policy.max_bytes {
+            // keep the newest events that fit within the limit
+            dropped.evicted += sqlx::query(
+                "DELETE FROM events WHERE client_id = ? AND seq IN (
+    SELECT seq FROM (
+        SELECT seq, SUM(LENGTH(CAST(event AS BLOB))) OVER (ORDER BY seq DESC) AS total
+        FROM events WHERE client_id = ?
+    ) WHERE total > ?
+)",
+            )
+            .bind(client_id)
+            .bind(client_id)
+            .bind(max_bytes as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(dropped)
     }
 }
 
@@ -118,8 +383,338 @@ pub mod tests {
             event_type: schema::EventType::Data,
             content: "{}".to_string(),
         };
+        let event = SequencedEvent { sequence: 1, event };
         pool.add_event(client_id, &event).await.unwrap();
-        let events = pool.get_events(client_id).await.unwrap();
-        assert_eq!(events[0].commit_id, event.commit_id);
+        let events = pool
+            .get_events(client_id, 0, BatchLimit::default())
+            .await
+            .unwrap();
+        assert_eq!(events[0].event.commit_id, event.event.commit_id);
+        assert_eq!(events[0].sequence, 1);
+        assert_eq!(pool.count_events(client_id).await.unwrap(), 1);
+        let cursor = events[0].sequence;
+        assert!(pool
+            .get_events(client_id, cursor, BatchLimit::default())
+            .await
+            .unwrap()
+            .is_empty());
+        pool.ack_events(client_id, cursor).await.unwrap();
+        assert!(pool
+            .get_events(client_id, 0, BatchLimit::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn can_limit_reads() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let client_id = "limited_client";
+        let mut events = vec![];
+        for sequence in 1..=3 {
+            let event = SequencedEvent {
+                sequence,
+                event: Event {
+                    commit_id: format!("limited_commit_id_{}", sequence),
+                    metadata: serde_json::Value::Null,
+                    event_type: schema::EventType::Data,
+                    content: "{}".to_string(),
+                },
+            };
+            pool.add_event(client_id, &event).await.unwrap();
+            events.push(event);
+        }
+        let read = |limit| pool.get_events(client_id, 0, limit);
+        let limit = BatchLimit {
+            max_events: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(read(limit).await.unwrap().len(), 2);
+        let limit = BatchLimit {
+            max_bytes: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(read(limit).await.unwrap().len(), 1);
+        let size = serde_json::to_string(&events[0].event).unwrap().len();
+        let limit = BatchLimit {
+            max_bytes: Some(size + 1),
+            ..Default::default()
+        };
+        assert_eq!(read(limit).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keys_events_by_client() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let event = Event {
+            commit_id: "shared_commit_id".to_string(),
+            metadata: serde_json::Value::Null,
+            event_type: schema::EventType::Data,
+            content: "{}".to_string(),
+        };
+        let event = SequencedEvent { sequence: 1, event };
+        assert!(pool.add_event("first_client", &event).await.unwrap());
+        assert!(!pool
+            .add_event(
+                "first_client",
+                &SequencedEvent {
+                    sequence: 2,
+                    ..event.clone()
+                },
+            )
+            .await
+            .unwrap());
+        assert!(pool.add_event("second_client", &event).await.unwrap());
+        assert_eq!(pool.count_events("first_client").await.unwrap(), 1);
+        assert_eq!(pool.count_events("second_client").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn migrates_existing_database() {
+        let _ = env_logger::try_init();
+
+        let dir = tmpdir::TmpDir::new("pers").await.unwrap();
+        let url = format!("sqlite://{}/existing.db", dir.as_ref().display());
+        Sqlite::create_database(&url).await.unwrap();
+        let pool = sqlx::sqlite::SqlitePool::connect(&url).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE events
+(
+    id          TEXT PRIMARY KEY NOT NULL,
+    client_id   TEXT             NOT NULL,
+    event       JSONB            NOT NULL
+);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let event = Event {
+            commit_id: "existing_commit_id".to_string(),
+            metadata: serde_json::Value::Null,
+            event_type: schema::EventType::Data,
+            content: "{}".to_string(),
+        };
+        sqlx::query("INSERT INTO events (id, client_id, event) VALUES (?, ?, ?)")
+            .bind(&event.commit_id)
+            .bind("existing_client")
+            .bind(sqlx::types::Json(&event))
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let pool = SqlitePersistence::new_with_url(&url).await.unwrap();
+        let events = pool
+            .get_events("existing_client", 0, BatchLimit::default())
+            .await
+            .unwrap();
+        assert_eq!(events[0].event.commit_id, event.commit_id);
+        assert_eq!(events[0].sequence, 1);
+    }
+
+    #[tokio::test]
+    async fn can_save_feed_position() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let feed = "http://localhost:7007/";
+        assert_eq!(pool.get_feed_position(feed).await.unwrap(), None);
+        pool.set_feed_position(feed, "1").await.unwrap();
+        pool.set_feed_position(feed, "2").await.unwrap();
+        assert_eq!(
+            pool.get_feed_position(feed).await.unwrap(),
+            Some("2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn can_save_registrations() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let mut registration = Registration {
+            client_id: "registered_client".to_string(),
+            filter: EventFilter::default(),
+            retention: RetentionPolicy::default(),
+            created_at: 1,
+            last_sequence: 0,
+            owner: None,
+        };
+        pool.add_registration(&registration).await.unwrap();
+        let event = Event {
+            commit_id: "registered_commit_id".to_string(),
+            metadata: serde_json::Value::Null,
+            event_type: schema::EventType::Data,
+            content: "{}".to_string(),
+        };
+        pool.add_event(
+            &registration.client_id,
+            &SequencedEvent {
+                sequence: 3,
+                event: event.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        // a redelivered event does not take its sequence
+        pool.add_event(
+            &registration.client_id,
+            &SequencedEvent { sequence: 4, event },
+        )
+        .await
+        .unwrap();
+        registration.last_sequence = 3;
+        registration.filter.models = vec!["model".to_string()];
+        // the first owner to register claims the client
+        registration.owner = Some("did:key:owner".to_string());
+        pool.add_registration(&Registration {
+            created_at: 2,
+            last_sequence: 0,
+            ..registration.clone()
+        })
+        .await
+        .unwrap();
+        pool.add_registration(&Registration {
+            owner: Some("did:key:other".to_string()),
+            last_sequence: 0,
+            ..registration.clone()
+        })
+        .await
+        .unwrap();
+        let registrations = pool.get_registrations().await.unwrap();
+        assert!(registrations.contains(&registration));
+        pool.delete_client(&registration.client_id, false)
+            .await
+            .unwrap();
+        assert!(!pool
+            .get_registrations()
+            .await
+            .unwrap()
+            .contains(&registration));
+    }
+
+    #[tokio::test]
+    async fn can_delete_client() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let client_id = "deleted_client";
+        let event = Event {
+            commit_id: "deleted_commit_id".to_string(),
+            metadata: serde_json::Value::Null,
+            event_type: schema::EventType::Data,
+            content: "{}".to_string(),
+        };
+        pool.add_event(client_id, &SequencedEvent { sequence: 1, event })
+            .await
+            .unwrap();
+        pool.delete_client(client_id, true).await.unwrap();
+        assert!(pool
+            .get_events(client_id, 0, BatchLimit::default())
+            .await
+            .unwrap()
+            .is_empty());
+        let (archived,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM archived_events WHERE client_id = ?")
+                .bind(client_id)
+                .fetch_one(&pool.pool)
+                .await
+                .unwrap();
+        assert_eq!(archived, 1);
+    }
+
+    #[tokio::test]
+    async fn can_apply_retention() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let client_id = "retained_client";
+        let mut events = vec![];
+        for sequence in 1..=4 {
+            let event = Event {
+                commit_id: format!("retained_commit_id_{}", sequence),
+                metadata: serde_json::Value::Null,
+                event_type: schema::EventType::Data,
+                content: "{}".to_string(),
+            };
+            pool.add_event(
+                client_id,
+                &SequencedEvent {
+                    sequence,
+                    event: event.clone(),
+                },
+            )
+            .await
+            .unwrap();
+            events.push(event);
+        }
+        sqlx::query("UPDATE events SET stream_id = 'stream' WHERE id IN (?, ?)")
+            .bind(&events[0].commit_id)
+            .bind(&events[1].commit_id)
+            .execute(&pool.pool)
+            .await
+            .unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        let dropped = pool
+            .apply_retention(
+                client_id,
+                &RetentionPolicy {
+                    compact: true,
+                    ..Default::default()
+                },
+                now,
+            )
+            .await
+            .unwrap();
+        assert_eq!(dropped.compacted, 1);
+        let dropped = pool
+            .apply_retention(
+                client_id,
+                &RetentionPolicy {
+                    max_events: Some(2),
+                    ..Default::default()
+                },
+                now,
+            )
+            .await
+            .unwrap();
+        assert_eq!(dropped.evicted, 1);
+        let size = serde_json::to_string(&events[3]).unwrap().len() as u64;
+        let dropped = pool
+            .apply_retention(
+                client_id,
+                &RetentionPolicy {
+                    max_bytes: Some(size),
+                    ..Default::default()
+                },
+                now,
+            )
+            .await
+            .unwrap();
+        assert_eq!(dropped.evicted, 1);
+        let remaining = pool
+            .get_events(client_id, 0, BatchLimit::default())
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].event.commit_id, events[3].commit_id);
+        let dropped = pool
+            .apply_retention(
+                client_id,
+                &RetentionPolicy {
+                    max_age_seconds: Some(60),
+                    ..Default::default()
+                },
+                now + 120,
+            )
+            .await
+            .unwrap();
+        assert_eq!(dropped.expired, 1);
+        assert_eq!(pool.count_events(client_id).await.unwrap(), 0);
     }
 }
@@ -0,0 +1,424 @@
+use super::{stream_id, BatchLimit, EventRow, Persistence, Registration, RegistrationRow};
+use crate::Error;
+use schema::{DroppedEvents, RetentionPolicy, SequencedEvent};
+use sqlx::Postgres;
+
+#[derive(Clone)]
+pub struct PostgresPersistence {
+    pool: sqlx::Pool<Postgres>,
+}
+
+impl PostgresPersistence {
+    pub async fn new_with_url(db: &str) -> Result<Self, Error> {
+        tracing::info!("Using postgres database");
+        let pool = sqlx::postgres::PgPool::connect(db).await?;
+        sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait::async_trait]
+impl Persistence for PostgresPersistence {
+    async fn add_event(&self, client_id: &str, event: &SequencedEvent) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+        // an event already stored for this client is a redelivery from the feed
+        let added = sqlx::query(
+            "INSERT INTO events (client_id, seq, id, event, created_at, stream_id)
+VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (client_id, id) DO NOTHING",
+        )
+        .bind(client_id)
+        .bind(event.sequence)
+        .bind(&event.event.commit_id)
+        .bind(sqlx::types::Json(&event.event))
+        .bind(chrono::Utc::now().timestamp())
+        .bind(stream_id(&event.event))
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            == 1;
+        if added {
+            sqlx::query(
+                "UPDATE registrations SET last_sequence = $1
+WHERE client_id = $2 AND last_sequence < $1",
+            )
+            .bind(event.sequence)
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    async fn get_events(
+        &self,
+        client_id: &str,
+        cursor: i64,
+        limit: BatchLimit,
+    ) -> Result<Vec<SequencedEvent>, Error> {
+        // keep each event whose preceding events are still under the byte limit
+        let rows = sqlx::query_as::<_, EventRow>(
+            "SELECT seq, event FROM (
+    SELECT seq, event, OCTET_LENGTH(event::text) AS size,
+        SUM(OCTET_LENGTH(event::text)) OVER (ORDER BY seq) AS total
+    FROM events WHERE client_id = $1 AND seq > $2
+) AS sized WHERE total - size < $3 ORDER BY seq LIMIT $4",
+        )
+        .bind(client_id)
+        .bind(cursor)
+        .bind(limit.max_bytes())
+        .bind(limit.max_events.map(|n| n as i64))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(SequencedEvent::from).collect())
+    }
+
+    async fn ack_events(&self, client_id: &str, cursor: i64) -> Result<(), Error> {
+        sqlx::query("DELETE FROM events WHERE client_id = $1 AND seq <= $2")
+            .bind(client_id)
+            .bind(cursor)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn count_events(&self, client_id: &str) -> Result<i64, Error> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM events WHERE client_id = $1")
+            .bind(client_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    async fn set_feed_position(&self, feed: &str, position: &str) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO feed_positions (feed, position) VALUES ($1, $2)
+ON CONFLICT (feed) DO UPDATE SET position = excluded.position",
+        )
+        .bind(feed)
+        .bind(position)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_feed_position(&self, feed: &str) -> Result<Option<String>, Error> {
+        let position: Option<(String,)> =
+            sqlx::query_as("SELECT position FROM feed_positions WHERE feed = $1")
+                .bind(feed)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(position.map(|(p,)| p))
+    }
+
+    async fn add_registration(&self, registration: &Registration) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO registrations (client_id, filter, retention, created_at, last_sequence, owner)
+VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (client_id) DO UPDATE SET filter = excluded.filter, retention = excluded.retention,
+    owner = COALESCE(registrations.owner, excluded.owner)",
+        )
+        .bind(&registration.client_id)
+        .bind(sqlx::types::Json(&registration.filter))
+        .bind(sqlx::types::Json(&registration.retention))
+        .bind(registration.created_at)
+        .bind(registration.last_sequence)
+        .bind(&registration.owner)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_registrations(&self) -> Result<Vec<Registration>, Error> {
+        let rows = sqlx::query_as::<_, RegistrationRow>(
+            "SELECT client_id, filter, retention, created_at, last_sequence, owner FROM registrations
+ORDER BY client_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Registration::from).collect())
+    }
+
+    async fn delete_client(&self, client_id: &str, archive: bool) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        if archive {
+            sqlx::query(
+                "INSERT INTO archived_events (seq, id, client_id, event, archived_at)
+SELECT seq, id, client_id, event, $1 FROM events WHERE client_id = $2",
+            )
+            .bind(chrono::Utc::now().timestamp())
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("DELETE FROM events WHERE client_id = $1")
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM registrations WHERE client_id = $1")
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn apply_retention(
+        &self,
+        client_id: &str,
+        policy: &RetentionPolicy,
+        now: i64,
+    ) -> Result<DroppedEvents, Error> {
+        let mut dropped = DroppedEvents::default();
+        let mut tx = self.pool.begin().await?;
+        // compact first so the count and size limits apply to the events that remain
+        if policy.compact {
+            dropped.compacted = sqlx::query(
+                "DELETE FROM events WHERE client_id = $1 AND seq < (
+    SELECT MAX(seq) FROM events AS latest
+    WHERE latest.client_id = events.client_id AND latest.stream_id = events.stream_id
+)",
+            )
+            .bind(client_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        if let Some(max_age) = policy.max_age_seconds {
+            dropped.expired =
+                sqlx::query("DELETE FROM events WHERE client_id = $1 AND created_at < $2")
+                    .bind(client_id)
+                    .bind(now.saturating_sub(max_age as i64))
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+        }
+        if let Some(max_events) = policy.max_events {
+            dropped.evicted += sqlx::query(
+                "DELETE FROM events WHERE client_id = $1 AND seq <= (
+    SELECT seq FROM events WHERE client_id = $1 ORDER BY seq DESC LIMIT 1 OFFSET $2
+)",
+            )
+            .bind(client_id)
+            .bind(max_events as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        if let Some(max_bytes) = policy.max_bytes {
+            // keep the newest events that fit within the limit
+            dropped.evicted += sqlx::query(
+                "DELETE FROM events WHERE client_id = $1 AND seq IN (
+    SELECT seq FROM (
+        SELECT seq, SUM(OCTET_LENGTH(event::text)) OVER (ORDER BY seq DESC) AS total
+        FROM events WHERE client_id = $1
+    ) AS sized WHERE total > $2
+)",
+            )
+            .bind(client_id)
+            .bind(max_bytes as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schema::{Event, EventFilter};
+
+    /// Tests run against the database at POSTGRES_TEST_URL, e.g.
+    /// `POSTGRES_TEST_URL=postgres://postgres@localhost/checkpointer cargo test -- --ignored`
+    async fn setup() -> PostgresPersistence {
+        let url = std::env::var("POSTGRES_TEST_URL").expect("POSTGRES_TEST_URL not set");
+        PostgresPersistence::new_with_url(&url).await.unwrap()
+    }
+
+    fn event(sequence: i64, commit_id: &str) -> SequencedEvent {
+        SequencedEvent {
+            sequence,
+            event: Event {
+                commit_id: commit_id.to_string(),
+                metadata: serde_json::Value::Null,
+                event_type: schema::EventType::Data,
+                content: "{}".to_string(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires postgres at POSTGRES_TEST_URL"]
+    async fn can_add_and_retrieve_events() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let client_id = format!("pg_client_{}", uuid::Uuid::new_v4());
+        let event = event(1, &uuid::Uuid::new_v4().to_string());
+        assert!(pool.add_event(&client_id, &event).await.unwrap());
+        assert!(!pool
+            .add_event(
+                &client_id,
+                &SequencedEvent {
+                    sequence: 2,
+                    ..event.clone()
+                },
+            )
+            .await
+            .unwrap());
+        let other_client = format!("pg_client_{}", uuid::Uuid::new_v4());
+        assert!(pool.add_event(&other_client, &event).await.unwrap());
+        assert_eq!(pool.count_events(&other_client).await.unwrap(), 1);
+        let events = pool
+            .get_events(&client_id, 0, BatchLimit::default())
+            .await
+            .unwrap();
+        assert_eq!(events[0].event.commit_id, event.event.commit_id);
+        assert_eq!(events[0].sequence, 1);
+        assert_eq!(pool.count_events(&client_id).await.unwrap(), 1);
+        let cursor = events[0].sequence;
+        assert!(pool
+            .get_events(&client_id, cursor, BatchLimit::default())
+            .await
+            .unwrap()
+            .is_empty());
+        pool.ack_events(&client_id, cursor).await.unwrap();
+        assert!(pool
+            .get_events(&client_id, 0, BatchLimit::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires postgres at POSTGRES_TEST_URL"]
+    async fn can_limit_reads() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let client_id = format!("pg_client_{}", uuid::Uuid::new_v4());
+        for sequence in 1..=3 {
+            pool.add_event(
+                &client_id,
+                &event(sequence, &uuid::Uuid::new_v4().to_string()),
+            )
+            .await
+            .unwrap();
+        }
+        let limit = BatchLimit {
+            max_bytes: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            pool.get_events(&client_id, 0, limit).await.unwrap().len(),
+            1
+        );
+        let limit = BatchLimit {
+            max_events: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            pool.get_events(&client_id, 0, limit).await.unwrap().len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires postgres at POSTGRES_TEST_URL"]
+    async fn can_save_state_and_delete_client() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let feed = format!("http://{}/", uuid::Uuid::new_v4());
+        pool.set_feed_position(&feed, "1").await.unwrap();
+        pool.set_feed_position(&feed, "2").await.unwrap();
+        assert_eq!(
+            pool.get_feed_position(&feed).await.unwrap(),
+            Some("2".to_string())
+        );
+
+        let registration = Registration {
+            client_id: format!("pg_client_{}", uuid::Uuid::new_v4()),
+            filter: EventFilter::default(),
+            retention: RetentionPolicy::default(),
+            created_at: 1,
+            last_sequence: 0,
+            owner: Some("did:key:owner".to_string()),
+        };
+        pool.add_registration(&registration).await.unwrap();
+        assert!(pool
+            .get_registrations()
+            .await
+            .unwrap()
+            .contains(&registration));
+        pool.add_event(
+            &registration.client_id,
+            &event(5, &uuid::Uuid::new_v4().to_string()),
+        )
+        .await
+        .unwrap();
+        assert!(pool
+            .get_registrations()
+            .await
+            .unwrap()
+            .contains(&Registration {
+                last_sequence: 5,
+                ..registration.clone()
+            }));
+        pool.delete_client(&registration.client_id, true)
+            .await
+            .unwrap();
+        assert_eq!(pool.count_events(&registration.client_id).await.unwrap(), 0);
+        assert!(!pool
+            .get_registrations()
+            .await
+            .unwrap()
+            .contains(&registration));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires postgres at POSTGRES_TEST_URL"]
+    async fn can_apply_retention() {
+        let _ = env_logger::try_init();
+
+        let pool = setup().await;
+        let client_id = format!("pg_client_{}", uuid::Uuid::new_v4());
+        let mut commit_ids = vec![];
+        for sequence in 1..=3 {
+            let event = event(sequence, &uuid::Uuid::new_v4().to_string());
+            pool.add_event(&client_id, &event).await.unwrap();
+            commit_ids.push(event.event.commit_id);
+        }
+        sqlx::query("UPDATE events SET stream_id = 'stream' WHERE client_id = $1 AND seq < 3")
+            .bind(&client_id)
+            .execute(&pool.pool)
+            .await
+            .unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let policy = RetentionPolicy {
+            compact: true,
+            max_events: Some(1),
+            ..Default::default()
+        };
+        let dropped = pool
+            .apply_retention(&client_id, &policy, now)
+            .await
+            .unwrap();
+        assert_eq!(dropped.compacted, 1);
+        assert_eq!(dropped.evicted, 1);
+        let policy = RetentionPolicy {
+            max_age_seconds: Some(60),
+            ..Default::default()
+        };
+        let dropped = pool
+            .apply_retention(&client_id, &policy, now + 120)
+            .await
+            .unwrap();
+        assert_eq!(dropped.expired, 1);
+        assert_eq!(pool.count_events(&client_id).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::batcher::{Batcher, ReportedErrors};
+use crate::errors::Error;
+use crate::persistence::BatchLimit;
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use schema::SequencedEvent;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How long a stream waits for events before sending a keep alive
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+const STREAM_BATCH_LIMIT: usize = 100;
+/// How long a stream waits before reading again after an error
+const ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFormat {
+    #[default]
+    Sse,
+    Ndjson,
+}
+
+impl StreamFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Sse => "text/event-stream",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn event(&self, event: &SequencedEvent) -> Result<String, Error> {
+        let json = serde_json::to_string(event)?;
+        Ok(match self {
+            Self::Sse => format!("id: {}\ndata: {}\n\n", event.sequence, json),
+            Self::Ndjson => format!("{}\n", json),
+        })
+    }
+
+    fn error(&self, e: &Error) -> String {
+        match self {
+            Self::Sse => format!(
+                "event: error\ndata: {}\n\n",
+                serde_json::json!(e.to_string())
+            ),
+            Self::Ndjson => format!("{}\n", serde_json::json!({ "error": e.to_string() })),
+        }
+    }
+
+    fn keep_alive(&self) -> &'static str {
+        match self {
+            Self::Sse => ": keep-alive\n\n",
+            Self::Ndjson => "",
+        }
+    }
+}
+
+/// Stream a client's stored events after `cursor` as they arrive. Streaming does not lease events,
+/// so consumers still acknowledge them to remove them from storage. The stream ends if the
+/// client's batcher is deleted or the batcher stops.
+pub fn batch_stream(
+    batcher: Batcher,
+    client_id: String,
+    cursor: i64,
+    format: StreamFormat,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    let reported = ReportedErrors::default();
+    futures_util::stream::unfold(Some(cursor), move |cursor| {
+        let batcher = batcher.clone();
+        let client_id = client_id.clone();
+        let reported = reported.clone();
+        async move {
+            let cursor = cursor?;
+            let res = batcher
+                .read_batch(
+                    &client_id,
+                    cursor,
+                    &reported,
+                    BatchLimit {
+                        max_events: Some(STREAM_BATCH_LIMIT),
+                        ..Default::default()
+                    },
+                    Some(KEEP_ALIVE),
+                )
+                .await;
+            match res {
+                Ok(batch) if batch.events.is_empty() => {
+                    Some((Ok(Bytes::from(format.keep_alive())), Some(cursor)))
+                }
+                Ok(batch) => {
+                    let chunk: Result<String, Error> =
+                        batch.events.iter().map(|ev| format.event(ev)).collect();
+                    match chunk {
+                        Ok(chunk) => Some((Ok(Bytes::from(chunk)), batch.cursor.or(Some(cursor)))),
+                        Err(e) => Some((Err(e), None)),
+                    }
+                }
+                Err(Error::NotFound(_)) | Err(Error::NotRunning) => None,
+                Err(e) => {
+                    tracing::warn!("Error streaming events for {}: {}", client_id, e);
+                    tokio::time::sleep(ERROR_BACKOFF).await;
+                    Some((Ok(Bytes::from(format.error(&e))), Some(cursor)))
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batcher::BatcherOptions;
+    use crate::persistence::SqlitePersistence;
+    use futures_util::StreamExt;
+    use schema::{Event, EventType};
+    use std::sync::Arc;
+
+    #[test]
+    fn formats_events() {
+        let event = SequencedEvent {
+            sequence: 3,
+            event: Event {
+                commit_id: "commit".to_string(),
+                event_type: EventType::Data,
+                content: "{}".to_string(),
+                metadata: serde_json::Value::Null,
+            },
+        };
+        let sse = StreamFormat::Sse.event(&event).unwrap();
+        assert!(sse.starts_with("id: 3\ndata: {"));
+        assert!(sse.ends_with("}\n\n"));
+        let ndjson = StreamFormat::Ndjson.event(&event).unwrap();
+        let parsed: SequencedEvent = serde_json::from_str(ndjson.trim_end()).unwrap();
+        assert_eq!(parsed.sequence, 3);
+        assert_eq!(ndjson.matches('\n').count(), 1);
+    }
+
+    #[tokio::test]
+    async fn ends_when_batcher_stops() {
+        let dir = tmpdir::TmpDir::new("stream").await.unwrap();
+        let url = format!("sqlite://{}/test.db", dir.as_ref().display());
+        let db = SqlitePersistence::new_with_url(&url).await.unwrap();
+        let batcher = Batcher::new_with_options(
+            Arc::new(db),
+            url::Url::parse("http://127.0.0.1:1").unwrap(),
+            BatcherOptions::default(),
+        );
+        batcher.shutdown().await.unwrap();
+        let stream = batch_stream(batcher, "test".to_string(), 0, StreamFormat::Ndjson);
+        let chunks: Vec<_> = tokio::time::timeout(Duration::from_secs(5), stream.collect())
+            .await
+            .unwrap();
+        assert!(chunks.is_empty());
+    }
+}
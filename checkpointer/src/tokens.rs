@@ -0,0 +1,156 @@
+use crate::calculator::CalculatorParameters;
+use crate::config::Settings;
+use crate::errors::Error;
+use ceramic_http_client::api::Pagination;
+use ceramic_http_client::ceramic_event::{ssi, DidDocument, JwkSigner, StreamId};
+use ceramic_http_client::remote::CeramicRemoteHttpClient;
+use ceramic_http_client::{FilterQuery, OperationFilter};
+use models::{Claims, PointMaterialization, AUDIENCE};
+use std::collections::{BTreeMap, HashMap};
+
+/// Materializations requested per query while collecting a holder's points
+const QUERY_PAGE_SIZE: u32 = 100;
+
+/// Issues short lived tokens, signed by the checkpointer's DID, asserting a holder's points
+pub struct TokenIssuer {
+    did: DidDocument,
+    jwk: ssi::jwk::JWK,
+    ttl: chrono::Duration,
+    materialization_model_id: StreamId,
+    cli: CeramicRemoteHttpClient<JwkSigner>,
+}
+
+impl TokenIssuer {
+    pub fn new(settings: &Settings, params: &CalculatorParameters) -> Result<Self, Error> {
+        let pk = settings
+            .did_private_key
+            .as_ref()
+            .ok_or_else(|| Error::custom("Invalid PRIVATE_KEY"))?;
+        Self::new_with_key(
+            DidDocument::new(&settings.did_document),
+            pk,
+            chrono::Duration::seconds(settings.token_ttl_seconds),
+            params.calculator.materialization_model_id.clone(),
+            CeramicRemoteHttpClient::new(params.signer.clone(), params.ceramic_url.clone()),
+        )
+    }
+
+    pub fn new_with_key(
+        did: DidDocument,
+        private_key: &str,
+        ttl: chrono::Duration,
+        materialization_model_id: StreamId,
+        cli: CeramicRemoteHttpClient<JwkSigner>,
+    ) -> Result<Self, Error> {
+        let bytes = hex::decode(private_key).map_err(|_| Error::custom("Invalid PRIVATE_KEY"))?;
+        let jwk = ssi::jwk::ed25519_parse_private(&bytes)
+            .map_err(|e| Error::custom(format!("Invalid PRIVATE_KEY: {}", e)))?;
+        Ok(Self {
+            did,
+            jwk,
+            ttl,
+            materialization_model_id,
+            cli,
+        })
+    }
+
+    async fn points(&self, holder: &str) -> Result<BTreeMap<String, i64>, Error> {
+        let filter = || {
+            let mut where_filter = HashMap::new();
+            where_filter.insert(
+                "recipient".to_string(),
+                OperationFilter::EqualTo(holder.into()),
+            );
+            FilterQuery::Where(where_filter)
+        };
+        let mut materializations = vec![];
+        let mut after = None;
+        loop {
+            let resp = self
+                .cli
+                .query(
+                    &self.materialization_model_id,
+                    Some(filter()),
+                    Pagination::First {
+                        first: QUERY_PAGE_SIZE,
+                        after: after.take(),
+                    },
+                )
+                .await?;
+            let cursor = resp.edges.last().map(|edge| edge.cursor.clone());
+            for edge in resp.edges {
+                let mat: PointMaterialization = serde_json::from_value(edge.node.content)?;
+                materializations.push(mat);
+            }
+            match cursor {
+                Some(cursor) if resp.page_info.has_next_page => after = Some(cursor),
+                _ => break,
+            }
+        }
+        Ok(points_by_context(materializations))
+    }
+
+    pub async fn issue(&self, holder: &str) -> Result<String, Error> {
+        let points = self.points(holder).await?;
+        let now = chrono::Utc::now();
+        let claims = Claims {
+            iss: self.did.id.clone(),
+            sub: holder.to_string(),
+            aud: AUDIENCE.to_string(),
+            jti: uuid::Uuid::new_v4().to_string(),
+            iat: now.timestamp(),
+            exp: (now + self.ttl).timestamp(),
+            points,
+        };
+        tracing::debug!("Issuing token {} for {}", claims.jti, holder);
+        Ok(claims.sign(&self.jwk)?)
+    }
+}
+
+/// Points by context from materializations in the order ceramic indexed them. A context
+/// materialized more than once keeps its latest value.
+fn points_by_context(
+    materializations: impl IntoIterator<Item = PointMaterialization>,
+) -> BTreeMap<String, i64> {
+    let mut points = BTreeMap::new();
+    for mat in materializations {
+        if let Some(previous) = points.insert(mat.context.clone(), mat.value) {
+            tracing::warn!(
+                "Duplicate {} materializations for {}, using {} over {}",
+                mat.context,
+                mat.recipient,
+                mat.value,
+                previous
+            );
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn materialization(context: &str, value: i64) -> PointMaterialization {
+        PointMaterialization {
+            issuer: "ceramic-fluence".to_string(),
+            recipient: "did:key:holder".to_string(),
+            context: context.to_string(),
+            value,
+            point_claims_id: String::default(),
+        }
+    }
+
+    #[test]
+    fn keeps_latest_materialization_of_each_context() {
+        let points = points_by_context(vec![
+            materialization("all-events", 1),
+            materialization("referrals", 10),
+            materialization("all-events", 3),
+        ]);
+        assert_eq!(
+            points,
+            BTreeMap::from([("all-events".to_string(), 3), ("referrals".to_string(), 10)])
+        );
+    }
+}
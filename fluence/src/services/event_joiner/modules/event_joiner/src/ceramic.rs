@@ -1,5 +1,6 @@
 use crate::{curl, Http, CURL_DEFAULT_ARGUMENTS};
 use anyhow::Error;
+use calculator::{CollectionPage, COLLECTION_ENDPOINT};
 use ceramic_http_client::api::Pagination;
 use ceramic_http_client::ceramic_event::{DidDocument, JwkSigner, StreamId};
 use ceramic_http_client::{api, CeramicHttpClient, FilterQuery};
@@ -105,4 +106,8 @@ impl calculator::Ceramic for Ceramic {
         let stream_id = res.resolve("create_points")?.stream_id;
         Ok(stream_id)
     }
+
+    async fn collection(&self, request: serde_json::Value) -> Result<CollectionPage, Error> {
+        self.post(COLLECTION_ENDPOINT, request).await
+    }
 }
@@ -4,9 +4,11 @@ mod http;
 use http::Http;
 
 use crate::ceramic::Ceramic;
-use ceramic_http_client::ceramic_event::{DidDocument, StreamId};
+use ceramic_http_client::ceramic_event::{ssi, DidDocument, StreamId};
 use marine_rs_sdk::{marine, MountedBinaryStringResult};
-use schema::Event;
+use models::Claims;
+use schema::{Ack, Batch, EventFilter};
+use std::cell::RefCell;
 use std::str::FromStr;
 use url::Url;
 use wasm_rs_async_executor::single_threaded as executor;
@@ -28,7 +30,10 @@ pub struct ExecutionConfig {
     pub checkpointer_endpoint: String,
     pub attestation_issuer: String,
     pub attestation_model_id: String,
+    pub attestation_model_id_v2: String,
     pub materialization_model_id: String,
+    /// Completion rules in the `COMPLETION_RULES` format, e.g. `first-all-events=any:9`
+    pub completion_rules: String,
 }
 
 #[marine]
@@ -39,6 +44,27 @@ pub struct SseResponse {
 
 pub fn main() {}
 
+thread_local! {
+    /// Calculator of the last successful run and the settings it was built with. Once it has
+    /// loaded the stored attestations and materializations, every later attestation reaches it
+    /// through the batches, so runs with the same settings reuse it instead of loading again.
+    static CALCULATOR: RefCell<Option<(String, calculator::Calculator)>> = RefCell::new(None);
+}
+
+/// The settings a calculator is built from, to tell whether a kept calculator can be reused
+fn calculator_key(cfg: &ExecutionConfig) -> String {
+    [
+        cfg.public_key.as_str(),
+        cfg.ceramic_endpoint.as_str(),
+        cfg.attestation_issuer.as_str(),
+        cfg.attestation_model_id.as_str(),
+        cfg.attestation_model_id_v2.as_str(),
+        cfg.materialization_model_id.as_str(),
+        cfg.completion_rules.as_str(),
+    ]
+    .join("\n")
+}
+
 #[marine]
 pub fn process_events(cfg: ExecutionConfig) -> SseResponse {
     let _ = env_logger::try_init();
@@ -56,35 +82,80 @@ pub fn process_events(cfg: ExecutionConfig) -> SseResponse {
 }
 
 const BATCH_PATH: &str = "/api/v1/batch";
+const BATCH_WAIT_MS: u64 = 1000;
 
 const CURL_DEFAULT_ARGUMENTS: &[&str] = &["-H", "Content-Type: application/json", "-i"];
+/// Lifetime of the token authenticating this run to the checkpointer, which outlasts the run
+const API_TOKEN_TTL_SECONDS: i64 = 60;
 
 async fn try_process_events(cfg: ExecutionConfig) -> Result<SseResponse, anyhow::Error> {
     let ceramic_endpoint = Url::parse(&cfg.ceramic_endpoint)?;
     let checkpointer_endpoint = Url::parse(&cfg.checkpointer_endpoint)?;
+    let key = calculator_key(&cfg);
     let client_id = cfg.client_id;
-    let attestation_model_id = StreamId::from_str(&cfg.attestation_model_id)?;
-    let materialization_model_id = StreamId::from_str(&cfg.materialization_model_id)?;
-    let attestation_issuer = DidDocument::new(&cfg.attestation_issuer);
-
-    let did = DidDocument::new(&cfg.public_key);
-    let ceramic: Box<dyn calculator::Ceramic + Send + Sync> =
-        Box::new(Ceramic::new(did.clone(), &cfg.private_key, ceramic_endpoint).await?);
-    let mut calculator = calculator::Calculator::new(
-        calculator::CalculatorParameters {
-            attestation_issuer,
-            attestation_model_id,
-            materialization_model_id,
-        },
-        ceramic,
-    );
-
-    let cmd: Vec<_> = CURL_DEFAULT_ARGUMENTS
+    let mut attestation_models = vec![calculator::AttestationModel {
+        model_id: StreamId::from_str(&cfg.attestation_model_id)?,
+        version: calculator::AttestationVersion::V1,
+    }];
+    if !cfg.attestation_model_id_v2.is_empty() {
+        attestation_models.push(calculator::AttestationModel {
+            model_id: StreamId::from_str(&cfg.attestation_model_id_v2)?,
+            version: calculator::AttestationVersion::V2,
+        });
+    }
+    let filter = EventFilter {
+        models: attestation_models
+            .iter()
+            .map(|m| m.model_id.to_string())
+            .collect(),
+        ..Default::default()
+    };
+    // a run that fails drops the calculator, so the next one loads the stored state again
+    let kept = CALCULATOR.with(|c| c.borrow_mut().take());
+    let mut calculator = match kept {
+        Some((kept_key, calculator)) if kept_key == key => calculator,
+        _ => {
+            let materialization_model_id = StreamId::from_str(&cfg.materialization_model_id)?;
+            let attestation_issuer = DidDocument::new(&cfg.attestation_issuer);
+            let did = DidDocument::new(&cfg.public_key);
+            let ceramic: Box<dyn calculator::Ceramic + Send + Sync> =
+                Box::new(Ceramic::new(did, &cfg.private_key, ceramic_endpoint).await?);
+            calculator::Calculator::new(
+                calculator::CalculatorParameters {
+                    attestation_issuer,
+                    attestation_models,
+                    materialization_model_id,
+                    referral_depth: calculator::DEFAULT_REFERRAL_DEPTH,
+                    referral_share_percent: calculator::DEFAULT_REFERRAL_SHARE_PERCENT,
+                    streaks: vec![],
+                    completions: calculator::CompletionRule::parse_list(&cfg.completion_rules)?,
+                },
+                ceramic,
+            )
+        }
+    };
+
+    let jwk = ssi::jwk::ed25519_parse_private(&hex::decode(&cfg.private_key)?)?;
+    let token = Claims::for_api(
+        &cfg.public_key,
+        chrono::Duration::seconds(API_TOKEN_TTL_SECONDS),
+    )
+    .sign(&jwk)?;
+    let default_arguments: Vec<_> = CURL_DEFAULT_ARGUMENTS
         .iter()
         .map(|s| s.to_string())
+        .chain(vec![
+            "-H".to_string(),
+            format!("Authorization: Bearer {}", token),
+        ])
+        .collect();
+
+    let cmd: Vec<_> = default_arguments
+        .iter()
+        .cloned()
         .chain(vec![
             "-d".to_string(),
-            format!(r#"{{"client_id":"{}"}}"#, client_id),
+            serde_json::json!({ "client_id": client_id, "filter": filter }).to_string(),
             checkpointer_endpoint.join(BATCH_PATH)?.to_string(),
         ])
         .collect();
@@ -101,27 +172,52 @@ async fn try_process_events(cfg: ExecutionConfig) -> Result<SseResponse, anyhow:
     }
     let now = std::time::Instant::now();
     let mut events_processed = 0u32;
-    let cmd: Vec<_> = CURL_DEFAULT_ARGUMENTS
+    let cmd: Vec<_> = default_arguments
         .iter()
-        .map(|s| s.to_string())
+        .cloned()
         .chain(vec![checkpointer_endpoint
-            .join(&format!("{}/{}", BATCH_PATH, client_id))?
+            .join(&format!(
+                "{}/{}?wait_ms={}",
+                BATCH_PATH, client_id, BATCH_WAIT_MS
+            ))?
             .to_string()])
         .collect();
 
+    let ack_endpoint = checkpointer_endpoint
+        .join(&format!("{}/{}/ack", BATCH_PATH, client_id))?
+        .to_string();
+
     loop {
         let res = curl(cmd.clone());
-        let events: Vec<Event> = Http::from(res)?;
-        log::debug!("Received {} ceramic events", events.len());
-        for event in events {
+        let batch: Batch = Http::from(res)?;
+        log::debug!("Received {} ceramic events", batch.events.len());
+        for event in batch.events {
             log::debug!("Processing event: {:?}", event);
-            calculator.process_event(event).await?;
+            calculator.process_event(event.event).await?;
             events_processed += 1;
         }
+        if let Some(cursor) = batch.cursor {
+            let ack: Vec<_> = default_arguments
+                .iter()
+                .cloned()
+                .chain(vec![
+                    "-d".to_string(),
+                    serde_json::to_string(&Ack { cursor })?,
+                    ack_endpoint.clone(),
+                ])
+                .collect();
+            let res = curl(ack);
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let (resp, _) = Http::parse(&res, &mut headers)?;
+            if resp.code != Some(200) {
+                anyhow::bail!("Error({:?}): {:?}", resp.code, resp.reason);
+            }
+        }
         if now.elapsed().as_secs() > 10 {
             break;
         }
     }
+    CALCULATOR.with(|c| *c.borrow_mut() = Some((key, calculator)));
     Ok(SseResponse {
         error: String::default(),
         events: events_processed,
@@ -145,7 +241,9 @@ mod tests {
             ceramic_endpoint: std::env::var("CERAMIC_URL")
                 .unwrap_or_else(|_| "http://localhost:8080".to_string()),
             attestation_model_id: "attestation".to_string(),
+            attestation_model_id_v2: String::default(),
             materialization_model_id: "materialization".to_string(),
+            completion_rules: calculator::DEFAULT_COMPLETION_RULES.to_string(),
         };
         let greeting = iface.process_events(cfg);
         assert!(greeting.error.is_empty());
@@ -1,8 +1,10 @@
 use ceramic_http_client::{
+    ceramic_event::{ssi, DidDocument, Jwk},
     schemars::{self, JsonSchema},
     GetRootSchema,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
@@ -24,6 +26,30 @@ pub struct PointAttestations {
 
 impl GetRootSchema for PointAttestations {}
 
+/// Version 2 of the attestation model. Field names are camel cased, and the attestations may name
+/// their recipient rather than relying on the stream controller.
+#[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PointAttestationsV2 {
+    pub issuer: String,
+    pub issuer_verification: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    pub data: Vec<PointAttestation>,
+}
+
+impl GetRootSchema for PointAttestationsV2 {}
+
+impl From<PointAttestationsV2> for PointAttestations {
+    fn from(value: PointAttestationsV2) -> Self {
+        Self {
+            issuer: value.issuer,
+            issuer_verification: value.issuer_verification,
+            data: value.data,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct PointMaterialization {
@@ -36,12 +62,121 @@ pub struct PointMaterialization {
 
 impl GetRootSchema for PointMaterialization {}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Claims of a points token, asserting the point totals per context of the holder in `sub`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Claims {
     pub iss: String,
     pub sub: String,
     pub aud: String,
     pub jti: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub points: BTreeMap<String, i64>,
 }
 
 pub const AUDIENCE: &str = "points";
+
+/// Audience of the tokens a DID issues itself to authenticate requests to the checkpointer API
+pub const API_AUDIENCE: &str = "checkpointer";
+
+impl Claims {
+    /// Claims authenticating `did` to the checkpointer API, to be signed with the DID's own key
+    pub fn for_api(did: &str, ttl: chrono::Duration) -> Self {
+        let now = chrono::Utc::now();
+        Self {
+            iss: did.to_string(),
+            sub: did.to_string(),
+            aud: API_AUDIENCE.to_string(),
+            jti: now.timestamp_nanos_opt().unwrap_or_default().to_string(),
+            iat: now.timestamp(),
+            exp: (now + ttl).timestamp(),
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// Sign the claims as a compact EdDSA JWS
+    pub fn sign(&self, jwk: &ssi::jwk::JWK) -> Result<String, anyhow::Error> {
+        let payload = serde_json::to_string(self)?;
+        Ok(ssi::jws::encode_sign(
+            ssi::jwk::Algorithm::EdDSA,
+            &payload,
+            jwk,
+        )?)
+    }
+}
+
+/// Verify a points token issued by `issuer` against its did:key, returning the claims if the
+/// token is intended for [`AUDIENCE`] and has not expired
+pub async fn verify_claims(token: &str, issuer: &str) -> Result<Claims, anyhow::Error> {
+    verify_claims_for(token, AUDIENCE, Some(issuer)).await
+}
+
+/// Verify a token against the did:key of its issuer, returning the claims if the token is intended
+/// for `audience`, has not expired and, when `issuer` is given, was issued by it
+pub async fn verify_claims_for(
+    token: &str,
+    audience: &str,
+    issuer: Option<&str>,
+) -> Result<Claims, anyhow::Error> {
+    let (_, payload) = ssi::jws::decode_unverified(token)?;
+    let unverified: Claims = serde_json::from_slice(&payload)?;
+    if let Some(issuer) = issuer {
+        if unverified.iss != issuer {
+            anyhow::bail!("Invalid issuer {}", unverified.iss);
+        }
+    }
+    let jwk = Jwk::new(&DidDocument::new(&unverified.iss)).await?;
+    let (_, payload) = ssi::jws::decode_verify(token, &jwk)?;
+    let claims: Claims = serde_json::from_slice(&payload)?;
+    if claims.aud != audience {
+        anyhow::bail!("Invalid audience {}", claims.aud);
+    }
+    if claims.exp < chrono::Utc::now().timestamp() {
+        anyhow::bail!("Token expired");
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// did:key of the ed25519 key in the first test vector of RFC 8032
+    const DID: &str = "did:key:z6MktwupdmLXVVqTzCw4i46r4uGyosGXRnR3XjN4Zq7oMMsw";
+    const SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    fn jwk() -> ssi::jwk::JWK {
+        let seed: Vec<u8> = (0..SEED.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&SEED[i..i + 2], 16).unwrap())
+            .collect();
+        ssi::jwk::ed25519_parse_private(&seed).unwrap()
+    }
+
+    fn claims(iss: &str) -> Claims {
+        let now = chrono::Utc::now().timestamp();
+        Claims {
+            iss: iss.to_string(),
+            sub: "did:key:holder".to_string(),
+            aud: AUDIENCE.to_string(),
+            jti: "1".to_string(),
+            iat: now,
+            exp: now + 60,
+            points: BTreeMap::from([("proof-of-data".to_string(), 10)]),
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_signed_claims() {
+        let token = claims(DID).sign(&jwk()).unwrap();
+        assert_eq!(verify_claims(&token, DID).await.unwrap(), claims(DID));
+        assert!(verify_claims_for(&token, API_AUDIENCE, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_claims_from_another_issuer() {
+        let forged = "did:key:z6MkhER5181mt9PBCrnVvL9AcdWyzSzj4PLgGVKSFjJ8obMN";
+        let token = claims(forged).sign(&jwk()).unwrap();
+        assert!(verify_claims(&token, DID).await.is_err());
+    }
+}
@@ -17,9 +17,173 @@ pub struct Event {
     pub metadata: serde_json::Value,
 }
 
+/// An event along with its position in a client's batch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SequencedEvent {
+    pub sequence: i64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// A batch of events. Acknowledging `cursor` commits every event in the batch.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Batch {
+    pub events: Vec<SequencedEvent>,
+    pub cursor: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ack {
+    pub cursor: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FeedState {
+    Connected,
+    Reconnecting,
+}
+
+/// The state of a client's batcher. Times are unix timestamps in seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatcherStatus {
+    pub client_id: String,
+    /// DID the batcher is bound to
+    #[serde(default)]
+    pub owner: Option<String>,
+    pub created_at: i64,
+    pub feed_state: FeedState,
+    /// Events received but not yet persisted
+    pub buffered_events: usize,
+    pub persisted_events: i64,
+    pub last_event_at: Option<i64>,
+    pub last_error: Option<String>,
+    /// Events dropped by the client's retention policy since the checkpointer started
+    #[serde(default)]
+    pub dropped_events: DroppedEvents,
+}
+
+/// Limits on the events stored for a client. Events beyond a limit are dropped oldest first.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_events: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Keep only the latest stored event for each stream
+    #[serde(default)]
+    pub compact: bool,
+}
+
+/// Counts of events dropped by a retention policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DroppedEvents {
+    /// Older than the maximum age
+    pub expired: u64,
+    /// Beyond the maximum count or size
+    pub evicted: u64,
+    /// Replaced by a later event for the same stream
+    pub compacted: u64,
+}
+
+impl DroppedEvents {
+    pub fn total(&self) -> u64 {
+        self.expired + self.evicted + self.compacted
+    }
+}
+
+impl std::ops::AddAssign for DroppedEvents {
+    fn add_assign(&mut self, other: Self) {
+        self.expired += other.expired;
+        self.evicted += other.evicted;
+        self.compacted += other.compacted;
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CeramicMetadata {
     pub controllers: Vec<String>,
     pub model: String,
 }
+
+/// A predicate on the JSON content of an event. Matches when the value at `pointer` equals
+/// `equals`, or when any value exists at `pointer` if `equals` is not set.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentPredicate {
+    pub pointer: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<serde_json::Value>,
+}
+
+impl ContentPredicate {
+    fn matches(&self, content: &serde_json::Value) -> bool {
+        match (content.pointer(&self.pointer), self.equals.as_ref()) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(value), Some(expected)) => value == expected,
+        }
+    }
+}
+
+/// Restricts the events a client receives. Empty lists match every event.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub controllers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub event_types: Vec<EventType>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content: Vec<ContentPredicate>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        if !self.event_types.is_empty() && !self.event_types.contains(&event.event_type) {
+            return false;
+        }
+        if !self.models.is_empty() {
+            let model = event.metadata.get("model").and_then(|m| m.as_str());
+            if !model.is_some_and(|m| self.models.iter().any(|f| f == m)) {
+                return false;
+            }
+        }
+        if !self.controllers.is_empty() {
+            let controllers = event
+                .metadata
+                .get("controllers")
+                .and_then(|c| c.as_array())
+                .cloned()
+                .unwrap_or_default();
+            if !controllers
+                .iter()
+                .filter_map(|c| c.as_str())
+                .any(|c| self.controllers.iter().any(|f| f == c))
+            {
+                return false;
+            }
+        }
+        if !self.content.is_empty() {
+            let content = match serde_json::from_str::<serde_json::Value>(&event.content) {
+                Ok(content) => content,
+                Err(_) => return false,
+            };
+            if !self.content.iter().all(|p| p.matches(&content)) {
+                return false;
+            }
+        }
+        true
+    }
+}
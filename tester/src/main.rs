@@ -53,6 +53,16 @@ async fn main() -> Result<(), anyhow::Error> {
                 "Created model: \n   PointAttestations: '{}'",
                 model.to_string(),
             );
+            let model_definition = ModelDefinition::new::<models::PointAttestationsV2>(
+                "PointAttestationsV2",
+                ModelAccountRelation::List,
+            )?;
+            let model = client.create_model(&model_definition).await?;
+            client.index_model(&model).await?;
+            tracing::info!(
+                "Created model: \n   PointAttestationsV2: '{}'",
+                model.to_string(),
+            );
             let model_definition = ModelDefinition::new::<models::PointMaterialization>(
                 "PointMaterialization",
                 ModelAccountRelation::List,
@@ -1,33 +1,211 @@
+use opentelemetry::trace::{
+    SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider as _,
+};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::TracerProvider;
+use sha2::{Digest, Sha256};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{EnvFilter, Layer};
 
-pub fn init_tracing() -> tracing_appender::non_blocking::WorkerGuard {
+/// How spans are exported in addition to being logged to stdout
+#[derive(Clone, Debug, Default)]
+pub struct TracingOptions {
+    /// Base url of an OTLP/HTTP collector, such as `http://localhost:4318`. Spans are only
+    /// exported when this is set.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl TracingOptions {
+    /// Options from `OTEL_EXPORTER_OTLP_ENDPOINT` and `OTEL_SERVICE_NAME`, naming the service
+    /// after the running binary by default
+    pub fn from_env() -> Self {
+        let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| {
+            std::env::current_exe()
+                .ok()
+                .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+                .unwrap_or_else(|| "ceramic-fluence".to_string())
+        });
+        Self {
+            otlp_endpoint: std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok(),
+            service_name,
+        }
+    }
+}
+
+/// Flushes logs and exported spans when dropped
+pub struct TracingGuard {
+    _writer: tracing_appender::non_blocking::WorkerGuard,
+    provider: Option<TracerProvider>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.as_ref() {
+            for res in provider.force_flush() {
+                if let Err(e) = res {
+                    eprintln!("Failed to export spans: {}", e);
+                }
+            }
+        }
+    }
+}
+
+pub fn init_tracing() -> TracingGuard {
+    init_tracing_with(TracingOptions::from_env())
+}
+
+pub fn init_tracing_with(options: TracingOptions) -> TracingGuard {
     let (non_blocking, guard) = tracing_appender::non_blocking(std::io::stdout());
     let fmt = std::env::var("RUST_LOG_FORMAT").unwrap_or_else(|_| "default".to_string());
-    match fmt.to_lowercase().as_str() {
-        "pretty" => {
-            tracing_subscriber::fmt()
-                .pretty()
-                .with_env_filter(EnvFilter::from_default_env())
-                .with_writer(non_blocking)
-                .finish()
-                .init();
-        }
-        "json" => {
-            tracing_subscriber::fmt()
-                .json() // should use JSON or opentelemetry in prod
-                .with_env_filter(EnvFilter::from_default_env())
-                .with_writer(non_blocking)
-                .finish()
-                .init();
+    let fmt_layer = match fmt.to_lowercase().as_str() {
+        "pretty" => tracing_subscriber::fmt::layer()
+            .pretty()
+            .with_writer(non_blocking)
+            .boxed(),
+        "json" => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(non_blocking)
+            .boxed(),
+        _f => tracing_subscriber::fmt::layer()
+            .with_writer(non_blocking)
+            .boxed(),
+    };
+    let provider = options.otlp_endpoint.as_deref().and_then(|endpoint| {
+        match otlp_provider(endpoint, &options.service_name) {
+            Ok(provider) => Some(provider),
+            Err(e) => {
+                // tracing is not running yet, so report on stderr and continue without export
+                eprintln!("Failed to create OTLP exporter for {}: {}", endpoint, e);
+                None
+            }
         }
-        _f => {
-            tracing_subscriber::fmt()
-                .with_env_filter(EnvFilter::from_default_env())
-                .with_writer(non_blocking)
-                .finish()
-                .init();
+    });
+    let otel_layer = provider.as_ref().map(|p| {
+        tracing_opentelemetry::layer()
+            .with_tracer(p.tracer("ceramic-fluence"))
+            .with_filter(export_filter())
+    });
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(EnvFilter::from_default_env()))
+        .with(otel_layer)
+        .init();
+    TracingGuard {
+        _writer: guard,
+        provider,
+    }
+}
+
+/// Spans exported to the collector: those enabled by `RUST_LOG`, which defaults to errors only,
+/// along with the info level event spans
+fn export_filter() -> EnvFilter {
+    EnvFilter::from_default_env().add_directive(
+        format!("{}=info", module_path!())
+            .parse()
+            .expect("event span directive should be valid"),
+    )
+}
+
+/// A provider exporting spans in batches to the OTLP/HTTP collector at `endpoint`
+pub fn otlp_provider(
+    endpoint: &str,
+    service_name: &str,
+) -> Result<TracerProvider, opentelemetry::trace::TraceError> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint.trim_end_matches('/'))
+        .build_span_exporter()?;
+    let config =
+        opentelemetry_sdk::trace::config().with_resource(opentelemetry_sdk::Resource::new(vec![
+            KeyValue::new("service.name", service_name.to_string()),
+        ]));
+    // the exporter runs on its own thread, so it works under either tokio runtime flavor
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
+        .with_config(config)
+        .build())
+}
+
+/// Trace shared by every span for a commit, derived from the commit id so that services can
+/// follow an event without passing trace context along with it
+pub fn event_trace_id(commit_id: &str) -> TraceId {
+    let digest = Sha256::digest(commit_id.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    TraceId::from_bytes(bytes)
+}
+
+/// A span for one stage of an event's journey, from its receipt on the feed to the calculator's
+/// writes. The spans for a commit share a trace, under a root span that is never exported.
+pub fn event_span(stage: &'static str, commit_id: &str) -> tracing::Span {
+    let span = tracing::info_span!(
+        "event",
+        otel.name = stage,
+        commit_id,
+        client_id = tracing::field::Empty,
+        sequence = tracing::field::Empty,
+    );
+    let digest = Sha256::digest(commit_id.as_bytes());
+    let mut span_id = [0u8; 8];
+    span_id.copy_from_slice(&digest[16..24]);
+    let root = SpanContext::new(
+        event_trace_id(commit_id),
+        SpanId::from_bytes(span_id),
+        TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+    span.set_parent(opentelemetry::Context::new().with_remote_span_context(root));
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn event_spans_share_a_trace_per_commit() {
+        assert_eq!(event_trace_id("commit"), event_trace_id("commit"));
+        assert_ne!(event_trace_id("commit"), event_trace_id("other"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_event_spans_to_collector() {
+        // stands in for an OTLP/HTTP collector
+        let collector = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/traces"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1..)
+            .mount(&collector)
+            .await;
+
+        let provider = otlp_provider(&collector.uri(), "util-test").unwrap();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("test"))
+                .with_filter(export_filter()),
+        );
+        let context = tracing::subscriber::with_default(subscriber, || {
+            let span = event_span("receive_event", "commit");
+            span.in_scope(|| tracing::info!("received"));
+            span.context()
+        });
+        assert_eq!(
+            context.span().span_context().trace_id(),
+            event_trace_id("commit")
+        );
+        for res in provider.force_flush() {
+            res.unwrap();
         }
+        let requests = collector.received_requests().await.unwrap();
+        assert!(requests
+            .iter()
+            .any(|req| req.body.windows(13).any(|w| w == b"receive_event")));
     }
-    guard
 }